@@ -0,0 +1,414 @@
+use std::fmt;
+use std::io;
+
+use bincode::Options;
+use idms::secure::sym::SymContext;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::security::{OwnedSealedMessage, SealedMessage};
+
+/// Frames are a big-endian `u32` length followed by that many bytes of
+/// bincode-encoded `SealedMessage`.
+pub const HEADER_LEN: usize = 4;
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
+    EmptyFrame,
+    Malformed(bincode::Error),
+    /// A sealed frame failed to open, or came out of order.
+    Unauthentic,
+    /// The channel has sealed as many frames as its counter can number.
+    Exhausted,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "frame i/o failed: {}", err),
+            Self::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+            Self::EmptyFrame => write!(f, "empty frame"),
+            Self::Malformed(err) => write!(f, "malformed message: {}", err),
+            Self::Unauthentic => write!(f, "frame failed to authenticate"),
+            Self::Exhausted => write!(f, "channel is out of nonces"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Malformed(err)
+    }
+}
+
+pub fn encode(message: &SealedMessage<'_>) -> Result<Vec<u8>, CodecError> {
+    Ok(bincode::options().serialize(message)?)
+}
+
+/// Plain frames are only for tests: the server seals every frame it
+/// exchanges.
+#[cfg(test)]
+pub fn decode(frame: &[u8]) -> Result<SealedMessage<'_>, CodecError> {
+    Ok(bincode::options().deserialize(frame)?)
+}
+
+pub fn decode_owned(frame: &[u8]) -> Result<OwnedSealedMessage, CodecError> {
+    Ok(bincode::options().deserialize(frame)?)
+}
+
+/// Reads length-prefixed frames from any tokio reader, e.g. the read half of
+/// a `TcpStream` or `UnixStream`.
+pub struct FrameReader<R> {
+    inner: R,
+    max_frame: usize,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_max_frame(inner, DEFAULT_MAX_FRAME)
+    }
+
+    pub fn with_max_frame(inner: R, max_frame: usize) -> Self {
+        Self {
+            inner,
+            max_frame,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns `Ok(None)` when the peer closes the stream between frames.
+    pub async fn read_frame(&mut self) -> Result<Option<&[u8]>, CodecError> {
+        let mut header = [0u8; HEADER_LEN];
+        if self.inner.read(&mut header[..1]).await? == 0 {
+            return Ok(None);
+        }
+        self.inner.read_exact(&mut header[1..]).await?;
+
+        let len = u32::from_be_bytes(header) as usize;
+        if len == 0 {
+            return Err(CodecError::EmptyFrame);
+        }
+        if len > self.max_frame {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame,
+            });
+        }
+
+        self.buf.resize(len, 0);
+        self.inner.read_exact(&mut self.buf).await?;
+        Ok(Some(&self.buf))
+    }
+
+    #[cfg(test)]
+    pub async fn read_message(&mut self) -> Result<Option<SealedMessage<'_>>, CodecError> {
+        match self.read_frame().await? {
+            Some(frame) => Ok(Some(decode(frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(test)]
+    pub async fn read_owned(&mut self) -> Result<Option<OwnedSealedMessage>, CodecError> {
+        match self.read_frame().await? {
+            Some(frame) => Ok(Some(decode_owned(frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a frame sealed by the peer's half of a secure channel. Frames
+    /// have to arrive in the order they were sealed.
+    pub async fn read_sealed(
+        &mut self,
+        receiver: &mut SymContext,
+    ) -> Result<Option<OwnedSealedMessage>, CodecError> {
+        let mut frame = match self.read_frame().await? {
+            Some(frame) => frame.to_vec(),
+            None => return Ok(None),
+        };
+        let plaintext = receiver
+            .decrypt(&[], &mut frame)
+            .map_err(|_| CodecError::Unauthentic)?;
+        Ok(Some(decode_owned(plaintext)?))
+    }
+
+    /// Feeds every message on the stream into `tx`, e.g. a `SocketGuard`'s
+    /// inbound channel, until the peer hangs up or the receiver is dropped.
+    #[cfg(test)]
+    pub async fn forward(mut self, tx: mpsc::Sender<OwnedSealedMessage>) -> Result<(), CodecError> {
+        while let Some(message) = self.read_owned().await? {
+            if tx.send(message).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `forward`, for frames sealed under `receiver`. Stops at the
+    /// first frame that doesn't open.
+    pub async fn forward_sealed(
+        mut self,
+        mut receiver: SymContext,
+        tx: mpsc::Sender<OwnedSealedMessage>,
+    ) -> Result<(), CodecError> {
+        while let Some(message) = self.read_sealed(&mut receiver).await? {
+            if tx.send(message).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes length-prefixed frames to any tokio writer.
+pub struct FrameWriter<W> {
+    inner: W,
+    max_frame: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_max_frame(inner, DEFAULT_MAX_FRAME)
+    }
+
+    pub fn with_max_frame(inner: W, max_frame: usize) -> Self {
+        Self { inner, max_frame }
+    }
+
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<(), CodecError> {
+        if frame.is_empty() {
+            return Err(CodecError::EmptyFrame);
+        }
+        if frame.len() > self.max_frame {
+            return Err(CodecError::FrameTooLarge {
+                len: frame.len(),
+                max: self.max_frame,
+            });
+        }
+
+        self.inner.write_u32(frame.len() as u32).await?;
+        self.inner.write_all(frame).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub async fn write_message(&mut self, message: &SealedMessage<'_>) -> Result<(), CodecError> {
+        self.write_frame(&encode(message)?).await
+    }
+
+    /// Seals `message` under this end's half of a secure channel.
+    pub async fn write_sealed(
+        &mut self,
+        sender: &mut SymContext,
+        message: &SealedMessage<'_>,
+    ) -> Result<(), CodecError> {
+        let mut frame = encode(message)?;
+        sender
+            .encrypt(&[], &mut frame)
+            .map_err(|_| CodecError::Exhausted)?;
+        self.write_frame(&frame).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{decode_owned, encode, CodecError, FrameReader, FrameWriter};
+    use crate::security::{OwnedSealedMessage, SealedMessage};
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const TEST_PASSWORD: &str = "TEST_PASSWORD";
+    const TEST_MESSAGE: &[u8] = b"Hello World";
+
+    fn every_variant() -> Vec<SealedMessage<'static>> {
+        vec![
+            SealedMessage::Nil,
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: &[7u8; 32],
+                signing_key: &[9u8; 32],
+                revoke: false,
+            },
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 7,
+                signature: &[1u8; 64],
+                message: TEST_MESSAGE,
+            },
+            SealedMessage::RedBox {
+                userid: TEST_USERNAME,
+                sent_at: 1_700_000_000,
+                message: TEST_MESSAGE,
+            },
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: &[7u8; 32],
+                signing_key: &[9u8; 32],
+            },
+            SealedMessage::Ack {
+                userid: TEST_USERNAME,
+            },
+            SealedMessage::Reject {
+                userid: TEST_USERNAME,
+                reason: "no",
+            },
+            SealedMessage::Locked {
+                userid: TEST_USERNAME,
+                seconds: 30,
+            },
+            SealedMessage::PublishPreKeys {
+                userid: TEST_USERNAME,
+                signed_prekey: &[7u8; 32],
+                prekey_signature: &[8u8; 64],
+                one_time_prekeys: &[9u8; 64],
+                signature: &[8u8; 64],
+            },
+            SealedMessage::FetchPreKeys {
+                userid: TEST_USERNAME,
+                subject: "SUBJECT",
+            },
+            SealedMessage::PreKeyBundle {
+                userid: TEST_USERNAME,
+                subject: "SUBJECT",
+                identity_key: &[7u8; 32],
+                signing_key: &[9u8; 32],
+                signed_prekey: &[7u8; 32],
+                prekey_signature: &[8u8; 64],
+                one_time_prekey: &[],
+            },
+            SealedMessage::PreKeysLow {
+                userid: TEST_USERNAME,
+                remaining: 3,
+            },
+            SealedMessage::Forward {
+                userid: TEST_USERNAME,
+                recipient: "TEST_RECIPIENT",
+                counter: 7,
+                signature: &[8u8; 64],
+                message: TEST_MESSAGE,
+            },
+            SealedMessage::Mail {
+                userid: "TEST_RECIPIENT",
+                id: 7,
+                sender: TEST_USERNAME,
+                counter: 7,
+                signature: &[8u8; 64],
+                message: TEST_MESSAGE,
+            },
+            SealedMessage::MailAck {
+                userid: "TEST_RECIPIENT",
+                id: 7,
+            },
+            SealedMessage::QueryPresence {
+                userid: TEST_USERNAME,
+                subject: "TEST_RECIPIENT",
+            },
+            SealedMessage::WatchPresence {
+                userid: TEST_USERNAME,
+                subject: "TEST_RECIPIENT",
+            },
+            SealedMessage::Presence {
+                userid: TEST_USERNAME,
+                subject: "TEST_RECIPIENT",
+                status: 2,
+            },
+            SealedMessage::Ticket {
+                userid: TEST_USERNAME,
+                ticket: &[7u8; 96],
+                seconds: 3600,
+            },
+            SealedMessage::Resume {
+                userid: TEST_USERNAME,
+                ticket: &[7u8; 96],
+                nonce: &[9u8; 32],
+                proof: &[8u8; 32],
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn round_trip_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut writer = FrameWriter::new(stream);
+            for message in every_variant() {
+                writer.write_message(&message).await.unwrap();
+            }
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = FrameReader::new(stream);
+        for expected in every_variant() {
+            assert_eq!(reader.read_message().await.unwrap(), Some(expected));
+        }
+        client.await.unwrap();
+        assert!(reader.read_message().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn owned_matches_borrowed_encoding() {
+        for message in every_variant() {
+            let owned = OwnedSealedMessage::from(message);
+            assert_eq!(owned.as_sealed(), message);
+            assert_eq!(decode_owned(&encode(&message).unwrap()).unwrap(), owned);
+            assert_eq!(
+                encode(&owned.as_sealed()).unwrap(),
+                encode(&message).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_frames() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::with_max_frame(server, 16);
+
+        client.write_u32(1024).await.unwrap();
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(CodecError::FrameTooLarge { len: 1024, max: 16 })
+        ));
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server);
+        let mut writer = FrameWriter::new(client);
+
+        writer.write_frame(&[0xFF; 8]).await.unwrap();
+        assert!(matches!(
+            reader.read_message().await,
+            Err(CodecError::Malformed(_))
+        ));
+
+        assert!(matches!(
+            FrameWriter::with_max_frame(tokio::io::sink(), 4)
+                .write_frame(&[0u8; 5])
+                .await,
+            Err(CodecError::FrameTooLarge { len: 5, max: 4 })
+        ));
+    }
+}
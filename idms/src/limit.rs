@@ -0,0 +1,37 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Counts what each key does over a sliding window, and turns away
+/// anything past `max` within it.
+pub struct RateLimit {
+    max: usize,
+    window: Duration,
+    /// When each key was let through, within the last `window`.
+    times: HashMap<String, VecDeque<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            times: HashMap::new(),
+        }
+    }
+
+    /// Counts one for `key`, unless it has had `max` within the last
+    /// `window` already.
+    pub fn allow(&mut self, key: &str, now: Instant) -> bool {
+        let since = now.checked_sub(self.window);
+        self.times.retain(|_, times| {
+            times.retain(|time| Some(*time) > since);
+            !times.is_empty()
+        });
+        let times = self.times.entry(key.to_owned()).or_default();
+        if times.len() >= self.max {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::security::OwnedSealedMessage;
+use crate::store::{RecordFile, StoreError, MASTER_KEY_LENGTH};
+
+#[derive(Debug)]
+pub enum MailError {
+    /// Taking the message would go over the recipient's quota.
+    Full,
+    /// No message with that id is waiting.
+    NotFound,
+    Store(StoreError),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "recipient's mailbox is full"),
+            Self::NotFound => write!(f, "no such message waiting"),
+            Self::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+impl From<StoreError> for MailError {
+    fn from(err: StoreError) -> Self {
+        Self::Store(err)
+    }
+}
+
+/// How much one mailbox holds, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailPolicy {
+    pub max_messages: usize,
+    /// Total size of the waiting messages.
+    pub max_bytes: usize,
+    /// Messages not acknowledged by then are dropped.
+    pub ttl: Duration,
+}
+
+impl Default for MailPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages: 100,
+            max_bytes: 1 << 20,
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// A message waiting for its recipient, as the sender signed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub id: u64,
+    pub sender: String,
+    pub counter: u64,
+    pub signature: Vec<u8>,
+    pub message: Vec<u8>,
+    pub expires_at: SystemTime,
+}
+
+impl Mail {
+    pub fn to_message(&self, recipient: &str) -> OwnedSealedMessage {
+        OwnedSealedMessage::Mail {
+            userid: recipient.to_owned(),
+            id: self.id,
+            sender: self.sender.clone(),
+            counter: self.counter,
+            signature: self.signature.clone(),
+            message: self.message.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Mailbox {
+    next_id: u64,
+    /// Everyone who has ever left mail here, acknowledged or not.
+    correspondents: BTreeSet<String>,
+    mail: VecDeque<Mail>,
+}
+
+impl Mailbox {
+    fn purge(&mut self, now: SystemTime) {
+        self.mail.retain(|mail| mail.expires_at > now);
+    }
+}
+
+/// Per-recipient mailboxes. Messages stay until the recipient acknowledges
+/// them or they expire, so one can be delivered more than once, e.g. when a
+/// connection drops before its `MailAck` arrives.
+///
+/// Recipients that are signed in somewhere subscribe, and get new messages
+/// pushed as they arrive.
+///
+/// Opened on a directory, each mailbox is kept in a file of its own, so a
+/// deposit or an acknowledgement only rewrites the one it touches.
+#[derive(Default)]
+pub struct MailStore {
+    dir: Option<(PathBuf, [u8; MASTER_KEY_LENGTH])>,
+    files: HashMap<String, RecordFile>,
+    boxes: HashMap<String, Mailbox>,
+    policy: MailPolicy,
+    subscribers: HashMap<String, Vec<mpsc::UnboundedSender<OwnedSealedMessage>>>,
+}
+
+impl MailStore {
+    /// Loads every mailbox in `dir`, creating it if need be.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        master_key: &[u8; MASTER_KEY_LENGTH],
+    ) -> Result<Self, StoreError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let mut store = Self {
+            dir: Some((dir.to_owned(), *master_key)),
+            ..Self::default()
+        };
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            // Left behind by a write that never got renamed into place.
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                continue;
+            }
+            let file = RecordFile::new(&path, master_key);
+            let mut records = file.load::<Mailbox>()?.into_iter();
+            let (userid, mailbox) = match (records.next(), records.next()) {
+                (Some(record), None) => record,
+                _ => return Err(StoreError::Corrupt(String::new())),
+            };
+            store.files.insert(userid.clone(), file);
+            store.boxes.insert(userid, mailbox);
+        }
+        Ok(store)
+    }
+
+    pub fn with_policy(mut self, policy: MailPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Queues `message` for `recipient` and pushes it to any connection it
+    /// is signed in on. Returns the id it is acknowledged by.
+    pub async fn deposit(
+        &mut self,
+        recipient: &str,
+        sender: &str,
+        counter: u64,
+        signature: Vec<u8>,
+        message: Vec<u8>,
+        now: SystemTime,
+    ) -> Result<u64, MailError> {
+        let policy = self.policy;
+        let mut mailbox = self.boxes.get(recipient).cloned().unwrap_or_default();
+        mailbox.purge(now);
+        let bytes: usize = mailbox.mail.iter().map(|mail| mail.message.len()).sum();
+        if mailbox.mail.len() >= policy.max_messages || bytes + message.len() > policy.max_bytes {
+            return Err(MailError::Full);
+        }
+
+        let mail = Mail {
+            id: mailbox.next_id,
+            sender: sender.to_owned(),
+            counter,
+            signature,
+            message,
+            expires_at: now + policy.ttl,
+        };
+        mailbox.next_id += 1;
+        mailbox.correspondents.insert(sender.to_owned());
+        mailbox.mail.push_back(mail.clone());
+        self.commit(recipient, mailbox).await?;
+
+        if let Some(subscribers) = self.subscribers.get_mut(recipient) {
+            subscribers.retain(|tx| tx.send(mail.to_message(recipient)).is_ok());
+        }
+        Ok(mail.id)
+    }
+
+    /// Whether `sender` has ever left mail for `recipient`.
+    pub fn has_written(&self, sender: &str, recipient: &str) -> bool {
+        self.boxes
+            .get(recipient)
+            .is_some_and(|mailbox| mailbox.correspondents.contains(sender))
+    }
+
+    /// Signs `userid` in on one more connection: new messages go to `tx`
+    /// until its receiver is dropped. Returns what is already waiting.
+    pub fn subscribe(
+        &mut self,
+        userid: &str,
+        tx: mpsc::UnboundedSender<OwnedSealedMessage>,
+        now: SystemTime,
+    ) -> Vec<Mail> {
+        let subscribers = self.subscribers.entry(userid.to_owned()).or_default();
+        subscribers.retain(|tx| !tx.is_closed());
+        subscribers.push(tx);
+
+        self.boxes.get(userid).map_or_else(Vec::new, |mailbox| {
+            mailbox
+                .mail
+                .iter()
+                .filter(|mail| mail.expires_at > now)
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Removes a delivered message.
+    pub async fn acknowledge(&mut self, userid: &str, id: u64) -> Result<(), MailError> {
+        let mut mailbox = self.boxes.get(userid).cloned().ok_or(MailError::NotFound)?;
+        let before = mailbox.mail.len();
+        mailbox.mail.retain(|mail| mail.id != id);
+        if mailbox.mail.len() == before {
+            return Err(MailError::NotFound);
+        }
+        self.commit(userid, mailbox).await
+    }
+
+    /// Emptied mailboxes stay on disk, so that ids are never handed out
+    /// twice.
+    async fn commit(&mut self, userid: &str, mailbox: Mailbox) -> Result<(), MailError> {
+        let old = self.boxes.insert(userid.to_owned(), mailbox);
+        if let Some((dir, master_key)) = &self.dir {
+            let file = self
+                .files
+                .entry(userid.to_owned())
+                .or_insert_with(|| RecordFile::new(dir.join(file_name(userid)), master_key));
+            let record = HashMap::from([(userid.to_owned(), &self.boxes[userid])]);
+            if let Err(err) = file.save(&record).await {
+                // Keep memory in line with what is on disk.
+                match old {
+                    Some(old) => self.boxes.insert(userid.to_owned(), old),
+                    None => self.boxes.remove(userid),
+                };
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Named after a digest of the recipient, as user ids needn't make safe file
+/// names.
+fn file_name(userid: &str) -> String {
+    digest(&SHA256, userid.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use tokio::sync::mpsc;
+
+    use super::{MailError, MailPolicy, MailStore};
+    use crate::security::OwnedSealedMessage;
+    use crate::testing::TempPath;
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const TEST_MESSAGE: &[u8] = b"Hello World";
+    const MASTER_KEY: &[u8; 32] = &[5u8; 32];
+
+    #[tokio::test]
+    async fn mailbox_keeps_mail_until_acknowledged() {
+        let path = TempPath::new("mail");
+        let now = SystemTime::now();
+
+        let policy = MailPolicy {
+            max_messages: 2,
+            max_bytes: 64,
+            ttl: Duration::from_secs(60),
+        };
+
+        let mut store = MailStore::open(&path, MASTER_KEY)
+            .unwrap()
+            .with_policy(policy);
+        for _ in 0..2 {
+            store
+                .deposit(
+                    TEST_USERNAME,
+                    "sender",
+                    0,
+                    vec![1],
+                    TEST_MESSAGE.to_vec(),
+                    now,
+                )
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            store
+                .deposit(
+                    TEST_USERNAME,
+                    "sender",
+                    0,
+                    vec![1],
+                    TEST_MESSAGE.to_vec(),
+                    now
+                )
+                .await,
+            Err(MailError::Full)
+        ));
+        // Every recipient's mail is kept in a file of its own.
+        store
+            .deposit(
+                "SOMEONE_ELSE",
+                "sender",
+                0,
+                vec![1],
+                TEST_MESSAGE.to_vec(),
+                now,
+            )
+            .await
+            .unwrap();
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 2);
+        assert!(store.has_written("sender", "SOMEONE_ELSE"));
+        assert!(!store.has_written("SOMEONE_ELSE", "sender"));
+        drop(store);
+
+        // Still there after a restart, until acknowledged or expired.
+        let mut store = MailStore::open(&path, MASTER_KEY)
+            .unwrap()
+            .with_policy(policy);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let waiting = store.subscribe(TEST_USERNAME, tx, now);
+        assert_eq!(waiting.len(), 2);
+        store
+            .acknowledge(TEST_USERNAME, waiting[0].id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.acknowledge(TEST_USERNAME, waiting[0].id).await,
+            Err(MailError::NotFound)
+        ));
+
+        // Subscribers get new mail as it arrives; expired mail makes room.
+        let later = now + Duration::from_secs(61);
+        let id = store
+            .deposit(
+                TEST_USERNAME,
+                "sender",
+                0,
+                vec![1],
+                TEST_MESSAGE.to_vec(),
+                later,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            OwnedSealedMessage::Mail { id: pushed, .. } if pushed == id
+        ));
+        assert_eq!(
+            store
+                .subscribe(TEST_USERNAME, mpsc::unbounded_channel().0, later)
+                .len(),
+            1
+        );
+    }
+}
@@ -1,238 +1,2637 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use idms::secure::sym::DecryptError;
+use idms::secure::{keys, red_box};
+use limit::RateLimit;
+use mailbox::{MailError, MailStore};
+use prekey::{PreKeyError, PreKeyStore};
+use presence::{KeepalivePolicy, Presence, PresenceRegistry};
 use rand_core::OsRng;
-use security::{DecodedMessage, EncryptionData, KeyStore, SealedMessage};
-use serde::{Deserialize, Serialize};
-use std::hash::Hash;
-use std::io::Error;
-use tokio::sync::{mpsc, watch};
-use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use replay::{ReplayError, SeenMessages};
+use ring::hmac;
+use ring::signature::ED25519_PUBLIC_KEY_LEN;
+use security::{DecodedMessage, EncryptionData, KeyStore, KeyStoreError, OwnedSealedMessage};
+use tokio::sync::{mpsc, Mutex};
+use user::{CredentialStore, LockoutPolicy, PasswordHash, RegistryError, User, UserRegistry};
+use x25519_dalek::{PublicKey, StaticSecret};
 
+mod codec;
+mod limit;
+mod mailbox;
+mod prekey;
+mod presence;
+mod replay;
 mod security;
+mod server;
+mod store;
+#[cfg(test)]
+mod testing;
+mod ticket;
 mod user;
-pub mod secure;
 
-use security::ForeignKeychain;
+use security::{
+    resume_proof_key, resumed_key, resumption_secret, ForeignKeychain, SealedMessage,
+    RESUME_NONCE_LEN,
+};
+use ticket::{TicketError, TicketKeeper};
+
+/// How many replies may wait for the client to read them. A client that
+/// falls this far behind is disconnected rather than buffered for.
+const REPLY_QUEUE: usize = 256;
+/// How many `Register`s one source may send per `REGISTRATION_WINDOW`, so
+/// that nobody can mint ids to get around per-user limits such as
+/// `MAX_FETCHES`.
+const MAX_REGISTRATIONS: usize = 5;
+const REGISTRATION_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Why the guard turned down a message.
+#[derive(Debug)]
+enum Rejection {
+    /// The id is unknown, the password is wrong or the account is locked.
+    /// Which one stays unsaid, so that nobody can probe `Sync` for ids.
+    Credentials,
+    Registry(RegistryError),
+    Keys(KeyStoreError),
+    BadSignature,
+    /// A `Communicate` that didn't pass: the sender is unknown or inactive,
+    /// the signature didn't verify, or the guard couldn't check. Which one
+    /// stays unsaid, as for `Credentials`.
+    Refused,
+    /// The user's keychain has expired.
+    Expired,
+    /// Too many wrong passwords on this connection. Try again after the
+    /// wait.
+    Locked(Duration),
+    PreKeys(PreKeyError),
+    Mail(MailError),
+    Ticket(TicketError),
+    /// Only a connection that has signed in as the user may do this.
+    SignedOut,
+    /// A key of the wrong length, or a point no DH should be done with.
+    BadKey,
+    Replayed(DecryptError),
+    /// Presence is only shared with users the subject has written to.
+    Hidden,
+    /// This source has sent `MAX_REGISTRATIONS` within the window.
+    TooManyRegistrations,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Credentials => write!(f, "credentials rejected"),
+            Self::Registry(err) => write!(f, "{}", err),
+            Self::Keys(err) => write!(f, "{}", err),
+            Self::BadSignature => write!(f, "signature did not verify"),
+            Self::Refused => write!(f, "message refused"),
+            Self::Expired => write!(f, "key expired, sync a new one"),
+            Self::Locked(wait) => write!(f, "locked for {}s", seconds(*wait)),
+            Self::PreKeys(err) => write!(f, "{}", err),
+            Self::Mail(err) => write!(f, "{}", err),
+            Self::Ticket(err) => write!(f, "{}", err),
+            Self::SignedOut => write!(f, "sign in with Register or Sync first"),
+            Self::BadKey => write!(f, "malformed or weak public key"),
+            Self::Replayed(err) => write!(f, "{}", err),
+            Self::Hidden => write!(f, "presence not shared with you"),
+            Self::TooManyRegistrations => write!(f, "too many registrations, try again later"),
+        }
+    }
+}
+
+impl From<RegistryError> for Rejection {
+    fn from(err: RegistryError) -> Self {
+        Self::Registry(err)
+    }
+}
+
+impl From<KeyStoreError> for Rejection {
+    fn from(err: KeyStoreError) -> Self {
+        Self::Keys(err)
+    }
+}
+
+impl From<PreKeyError> for Rejection {
+    fn from(err: PreKeyError) -> Self {
+        Self::PreKeys(err)
+    }
+}
+
+impl From<MailError> for Rejection {
+    fn from(err: MailError) -> Self {
+        Self::Mail(err)
+    }
+}
+
+impl From<TicketError> for Rejection {
+    fn from(err: TicketError) -> Self {
+        Self::Ticket(err)
+    }
+}
 
-struct SocketGuard<'a, KS: KeyStore<ID = &'a str>> {
+impl From<ReplayError> for Rejection {
+    fn from(err: ReplayError) -> Self {
+        match err {
+            ReplayError::Seen(err) => Self::Replayed(err),
+            ReplayError::Store(err) => Self::Keys(err.into()),
+        }
+    }
+}
+
+struct SocketGuard<KS: KeyStore<ID = String>, US: CredentialStore + UserRegistry> {
     guard_key: StaticSecret,
     keys: KS,
-    in_rx: mpsc::Receiver<SealedMessage<'a>>,
-    out_tx: watch::Sender<Option<SealedMessage<'a>>>,
-    out_rx: watch::Receiver<Option<SealedMessage<'a>>>,
+    users: US,
+    /// Shared with every other guard, so that no two hand out the same
+    /// one-time prekey.
+    prekeys: Arc<Mutex<PreKeyStore>>,
+    mail: Arc<Mutex<MailStore>>,
+    /// Who last signed in on this connection with `Register` or `Sync`.
+    session: Option<String>,
+    /// Mail and presence changes for `session` as they happen. Other
+    /// guards hold clones of `push_tx`.
+    push_tx: Option<mpsc::UnboundedSender<OwnedSealedMessage>>,
+    push_rx: Option<mpsc::UnboundedReceiver<OwnedSealedMessage>>,
+    /// A plain mutex, so that dropping the guard can sign it out.
+    presence: Arc<StdMutex<PresenceRegistry>>,
+    /// Shared with every other guard, so that a message passed on one
+    /// connection can't be replayed on another.
+    seen: Arc<Mutex<SeenMessages>>,
+    /// What this connection counts as towards `session`'s presence.
+    status: Presence,
+    /// Without one, connections never go idle or time out.
+    keepalive: Option<KeepalivePolicy>,
+    /// Without one, no tickets are issued and every `Resume` is turned
+    /// away.
+    tickets: Option<Arc<StdMutex<TicketKeeper>>>,
+    /// `Register`s per source, shared with every other guard. Without
+    /// one, registration is unlimited.
+    registrations: Option<Arc<StdMutex<RateLimit>>>,
+    /// Where the connection comes from, e.g. the peer's IP address.
+    source: String,
+    last_seen: Instant,
+    /// Applied to every keychain set at `Register` or `Sync`.
+    key_lifetime: Option<Duration>,
+    in_rx: mpsc::Receiver<OwnedSealedMessage>,
+    /// Replies are queued rather than overwritten, so a slow reader sees
+    /// every one of them, up to `REPLY_QUEUE`.
+    out_tx: mpsc::Sender<OwnedSealedMessage>,
+    out_rx: Option<mpsc::Receiver<OwnedSealedMessage>>,
+    /// Set once a reply didn't fit, which ends the connection.
+    overflowed: AtomicBool,
+    lockout: LockoutPolicy,
+    /// Wrong passwords on this connection, whoever they were for.
+    failures: u32,
+    retry_at: Option<Instant>,
 }
 
-impl<'a, KS: KeyStore<ID = &'a str>> SocketGuard<'a, KS> {
-    pub fn new(in_rx: mpsc::Receiver<SealedMessage<'a>>, keystore: KS) -> Self {
-        let (out_tx, out_rx) = watch::channel(None);
+impl<KS: KeyStore<ID = String>, US: CredentialStore + UserRegistry> SocketGuard<KS, US> {
+    pub fn new(in_rx: mpsc::Receiver<OwnedSealedMessage>, keystore: KS, users: US) -> Self {
+        let (out_tx, out_rx) = mpsc::channel(REPLY_QUEUE);
         Self {
             guard_key: StaticSecret::new(OsRng),
-            in_rx: in_rx,
+            in_rx,
             keys: keystore,
-            out_rx,
+            users,
+            prekeys: Arc::default(),
+            mail: Arc::default(),
+            session: None,
+            push_tx: None,
+            push_rx: None,
+            presence: Arc::default(),
+            seen: Arc::default(),
+            status: Presence::Offline,
+            keepalive: None,
+            tickets: None,
+            registrations: None,
+            source: String::new(),
+            last_seen: Instant::now(),
+            key_lifetime: None,
+            out_rx: Some(out_rx),
             out_tx,
+            overflowed: AtomicBool::new(false),
+            lockout: LockoutPolicy::default(),
+            failures: 0,
+            retry_at: None,
         }
     }
 
+    pub fn with_lockout_policy(mut self, lockout: LockoutPolicy) -> Self {
+        self.lockout = lockout;
+        self
+    }
+
+    /// Uses `guard_key` instead of a fresh one, so that every guard in a
+    /// process, and every restart, presents the same public key.
+    pub fn with_guard_key(mut self, guard_key: StaticSecret) -> Self {
+        self.guard_key = guard_key;
+        self
+    }
+
+    pub fn with_prekeys(mut self, prekeys: Arc<Mutex<PreKeyStore>>) -> Self {
+        self.prekeys = prekeys;
+        self
+    }
+
+    pub fn with_mail(mut self, mail: Arc<Mutex<MailStore>>) -> Self {
+        self.mail = mail;
+        self
+    }
+
+    pub fn with_presence(mut self, presence: Arc<StdMutex<PresenceRegistry>>) -> Self {
+        self.presence = presence;
+        self
+    }
+
+    pub fn with_seen(mut self, seen: Arc<Mutex<SeenMessages>>) -> Self {
+        self.seen = seen;
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: KeepalivePolicy) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn with_tickets(mut self, tickets: Arc<StdMutex<TicketKeeper>>) -> Self {
+        self.tickets = Some(tickets);
+        self
+    }
+
+    pub fn with_registrations(mut self, registrations: Arc<StdMutex<RateLimit>>) -> Self {
+        self.registrations = Some(registrations);
+        self
+    }
+
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn with_key_lifetime(mut self, lifetime: Duration) -> Self {
+        self.key_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Hands out the queue of replies for the client. Only the first call
+    /// gets it.
+    pub fn replies(&mut self) -> Option<mpsc::Receiver<OwnedSealedMessage>> {
+        self.out_rx.take()
+    }
+
+    #[cfg(test)]
+    pub fn users_mut(&mut self) -> &mut US {
+        &mut self.users
+    }
+
+    /// The key clients seal `RedBox` messages to.
+    #[cfg(test)]
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.guard_key)
+    }
+
+    #[cfg(test)]
     pub async fn next(&mut self) -> Option<DecodedMessage> {
-        if let Some(msg) = self.in_rx.recv().await {
-            return match msg {
-                SealedMessage::Nil => None,
-                SealedMessage::Sync {
-                    userid,
-                    password,
-                    public_key,
-                } => {
-                    self.sync(
-                        userid,
-                        password,
-                        PublicKey::from(public_key.iter().enumerate().fold(
-                            [0u8; 32],
-                            |mut acc, (idx, &v)| {
-                                acc[idx] = v;
-                                acc
-                            },
-                        )),
-                    );
-                    None
-                }
-                SealedMessage::Communicate {
-                    userid,
-                    message,
-                    signature,
-                } => Some(self.communicate(userid, message, signature)),
-                SealedMessage::RedBox { userid, message } => Some(self.red_box(userid, message)),
+        let msg = self.recv().await?;
+        self.handle(msg).await
+    }
+
+    /// Handles every inbound message until the sender hangs up or the
+    /// connection times out, passing each decoded one to `on_message`.
+    pub async fn serve(&mut self, mut on_message: impl FnMut(DecodedMessage)) {
+        while let Some(msg) = self.recv().await {
+            if let Some(decoded) = self.handle(msg).await {
+                on_message(decoded);
+            }
+        }
+    }
+
+    /// The next inbound message, or `None` once the connection has timed
+    /// out or stopped reading its replies. Anything pushed to the signed-in
+    /// user in the meantime is passed straight on, ahead of any reply.
+    async fn recv(&mut self) -> Option<OwnedSealedMessage> {
+        loop {
+            if self.overflowed.load(Ordering::Relaxed) {
+                self.set_status(Presence::Offline);
+                return None;
+            }
+            let push_rx = &mut self.push_rx;
+            let pushed = async move {
+                match push_rx {
+                    Some(push_rx) => push_rx.recv().await,
+                    None => std::future::pending().await,
+                }
             };
+            let idle_at = self
+                .keepalive
+                .filter(|_| self.status == Presence::Online)
+                .map(|keepalive| self.last_seen + keepalive.idle_after);
+            let timeout_at = self
+                .keepalive
+                .map(|keepalive| self.last_seen + keepalive.timeout);
+            tokio::select! {
+                biased;
+                Some(message) = pushed => self.queue(message),
+                msg = self.in_rx.recv() => {
+                    self.last_seen = Instant::now();
+                    if self.status == Presence::Idle {
+                        self.set_status(Presence::Online);
+                    }
+                    return msg;
+                }
+                _ = sleep_until(idle_at) => self.set_status(Presence::Idle),
+                _ = sleep_until(timeout_at) => {
+                    self.set_status(Presence::Offline);
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, msg: OwnedSealedMessage) -> Option<DecodedMessage> {
+        match msg {
+            OwnedSealedMessage::Sync {
+                userid,
+                password,
+                public_key,
+                signing_key,
+                revoke,
+            } => {
+                let result = self
+                    .sync(userid.clone(), password, &public_key, &signing_key, revoke)
+                    .await;
+                let synced = result.is_ok();
+                self.acknowledge(&userid, result);
+                if synced {
+                    self.issue_ticket(&userid).await;
+                    self.check_prekeys(&userid).await;
+                    self.sign_in(&userid).await;
+                }
+                None
+            }
+            OwnedSealedMessage::Communicate {
+                userid,
+                counter,
+                message,
+                signature,
+            } => {
+                let decoded = self.communicate(userid, counter, message, signature).await;
+                match &decoded.encryption_data {
+                    EncryptionData::Failed { target, .. } => {
+                        self.reject(target, &Rejection::Refused)
+                    }
+                    EncryptionData::Expired { username, .. } => {
+                        self.reject(username, &Rejection::Expired)
+                    }
+                    EncryptionData::Replayed { username, error } => {
+                        self.reject(username, &Rejection::Replayed(*error))
+                    }
+                    _ => {}
+                }
+                Some(decoded)
+            }
+            OwnedSealedMessage::RedBox {
+                userid,
+                sent_at,
+                message,
+            } => {
+                let decoded = self.red_box(userid, sent_at, message).await;
+                if let EncryptionData::Replayed { username, error } = &decoded.encryption_data {
+                    self.reject(username, &Rejection::Replayed(*error));
+                }
+                Some(decoded)
+            }
+            OwnedSealedMessage::Register {
+                userid,
+                password,
+                public_key,
+                signing_key,
+            } => {
+                let result = self
+                    .register(userid.clone(), password, &public_key, &signing_key)
+                    .await;
+                let registered = result.is_ok();
+                self.acknowledge(&userid, result);
+                if registered {
+                    self.issue_ticket(&userid).await;
+                    self.sign_in(&userid).await;
+                }
+                None
+            }
+            OwnedSealedMessage::PublishPreKeys {
+                userid,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekeys,
+                signature,
+            } => {
+                let result = self
+                    .publish_prekeys(
+                        &userid,
+                        signed_prekey,
+                        prekey_signature,
+                        &one_time_prekeys,
+                        &signature,
+                    )
+                    .await;
+                let published = result.is_ok();
+                self.acknowledge(&userid, result);
+                if published {
+                    self.check_prekeys(&userid).await;
+                }
+                None
+            }
+            OwnedSealedMessage::FetchPreKeys { userid, subject } => {
+                if let Err(rejection) = self.fetch_prekeys(&userid, &subject).await {
+                    self.reject(&userid, &rejection);
+                }
+                None
+            }
+            OwnedSealedMessage::Forward {
+                userid,
+                recipient,
+                counter,
+                signature,
+                message,
+            } => {
+                let result = self
+                    .forward(&userid, &recipient, counter, signature, message)
+                    .await;
+                self.acknowledge(&userid, result);
+                None
+            }
+            OwnedSealedMessage::MailAck { userid, id } => {
+                if let Err(rejection) = self.collect(&userid, id).await {
+                    self.reject(&userid, &rejection);
+                }
+                None
+            }
+            OwnedSealedMessage::QueryPresence { userid, subject } => {
+                if let Err(rejection) = self.report_presence(&userid, &subject, false).await {
+                    self.reject(&userid, &rejection);
+                }
+                None
+            }
+            OwnedSealedMessage::WatchPresence { userid, subject } => {
+                if let Err(rejection) = self.report_presence(&userid, &subject, true).await {
+                    self.reject(&userid, &rejection);
+                }
+                None
+            }
+            OwnedSealedMessage::Resume {
+                userid,
+                ticket,
+                nonce,
+                proof,
+            } => {
+                let result = self.resume(&userid, &ticket, &nonce, &proof).await;
+                let resumed = result.is_ok();
+                self.acknowledge(&userid, result);
+                if resumed {
+                    self.issue_ticket(&userid).await;
+                    self.check_prekeys(&userid).await;
+                    self.sign_in(&userid).await;
+                }
+                None
+            }
+            OwnedSealedMessage::Nil => {
+                // Receiving it already counted as activity.
+                self.send(SealedMessage::Nil);
+                None
+            }
+            // Anything only the guard should send.
+            _ => None,
+        }
+    }
+
+    fn acknowledge(&self, userid: &str, result: Result<(), Rejection>) {
+        match result {
+            Ok(()) => self.send(SealedMessage::Ack { userid }),
+            Err(Rejection::Locked(wait)) => self.send(SealedMessage::Locked {
+                userid,
+                seconds: seconds(wait),
+            }),
+            Err(rejection) => self.reject(userid, &rejection),
         }
+    }
+
+    fn reject(&self, userid: &str, rejection: &Rejection) {
+        self.send(SealedMessage::Reject {
+            userid,
+            reason: &rejection.to_string(),
+        });
+    }
+
+    fn send(&self, message: SealedMessage<'_>) {
+        self.queue(message.into());
+    }
 
-        None
+    fn queue(&self, message: OwnedSealedMessage) {
+        // Nobody listening for replies is not an error, but a listener
+        // that has stopped reading is.
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.out_tx.try_send(message) {
+            self.overflowed.store(true, Ordering::Relaxed);
+        }
     }
 
-    fn sync(&mut self, userid: &'a str, _password: &'a str, public_key: PublicKey) {
-        let ss = self.guard_key.diffie_hellman(&public_key);
+    /// Re-keys an existing, active user, provided the password matches.
+    /// With `revoke`, the replaced keychain stops verifying straight away.
+    async fn sync(
+        &mut self,
+        userid: String,
+        password: String,
+        public_key: &[u8],
+        signing_key: &[u8],
+        revoke: bool,
+    ) -> Result<(), Rejection> {
+        let public_key = parse_public_key(public_key)?;
+        let signing_key = parse_signing_key(signing_key)?;
+        self.check_password(&userid, &password).await?;
+        self.users.check_active(&userid).await?;
+        self.users
+            .attach_key(&userid, public_key.to_bytes())
+            .await?;
         self.keys
-            .set_key(
+            .rotate_key(userid.clone(), self.keychain(public_key, signing_key))
+            .await?;
+        if revoke {
+            self.keys.revoke_previous_key(&userid).await?;
+        }
+        Ok(())
+    }
+
+    /// Signs `userid` back in with a ticket instead of its password, and
+    /// moves its keychain to a shared key fresh from `nonce`.
+    async fn resume(
+        &mut self,
+        userid: &str,
+        ticket: &[u8],
+        nonce: &[u8],
+        proof: &[u8],
+    ) -> Result<(), Rejection> {
+        let tickets = self.tickets.clone().ok_or(TicketError::Invalid)?;
+        let opened = tickets
+            .lock()
+            .unwrap()
+            .open(userid, ticket, SystemTime::now())?;
+        if nonce.len() != RESUME_NONCE_LEN {
+            return Err(TicketError::Invalid.into());
+        }
+        let header = SealedMessage::Resume {
+            userid,
+            ticket: &[],
+            nonce: &[],
+            proof: &[],
+        }
+        .header();
+        hmac::verify(
+            &resume_proof_key(&opened.secret),
+            &[header.as_slice(), ticket, nonce].concat(),
+            proof,
+        )
+        .map_err(|_| TicketError::BadProof)?;
+
+        self.users.check_active(userid).await?;
+        let current = self.keys.get_key(&userid.to_owned()).await?;
+        if current.version != opened.key_id {
+            return Err(TicketError::Stale.into());
+        }
+        tickets.lock().unwrap().redeem(&opened, SystemTime::now())?;
+
+        let keychain = ForeignKeychain {
+            shared_key: resumed_key(&opened.secret, nonce),
+            created_at: SystemTime::now(),
+            expires_at: None,
+            ..current
+        };
+        let keychain = match self.key_lifetime {
+            Some(lifetime) => keychain.with_lifetime(lifetime),
+            None => keychain,
+        };
+        self.keys.rotate_key(userid.to_owned(), keychain).await?;
+        Ok(())
+    }
+
+    /// Sends `userid` a ticket for its current keychain, if this guard
+    /// hands them out.
+    async fn issue_ticket(&self, userid: &str) {
+        let tickets = match &self.tickets {
+            Some(tickets) => tickets,
+            None => return,
+        };
+        let keychain = match self.keys.get_key(&userid.to_owned()).await {
+            Ok(keychain) => keychain,
+            Err(_) => return,
+        };
+        let (ticket, lifetime) = {
+            let tickets = tickets.lock().unwrap();
+            let ticket = tickets.issue(
                 userid,
-                ForeignKeychain {
-                    public_key,
-                    shared_key: ss,
-                },
+                keychain.version,
+                resumption_secret(&keychain.shared_key),
+                SystemTime::now(),
+            );
+            (ticket, tickets.lifetime())
+        };
+        self.send(SealedMessage::Ticket {
+            userid,
+            ticket: &ticket,
+            seconds: lifetime.as_secs(),
+        });
+    }
+
+    /// Verifies the password unless the connection is backing off, and
+    /// counts wrong guesses against both the connection and the account.
+    /// Unknown ids, wrong passwords and locked accounts are all refused
+    /// alike, after the same PBKDF2 work.
+    async fn check_password(&mut self, userid: &str, password: &str) -> Result<(), Rejection> {
+        let wait = self.retry_at.map_or(Duration::ZERO, |at| {
+            at.saturating_duration_since(Instant::now())
+        });
+        if !wait.is_zero() {
+            return Err(Rejection::Locked(wait));
+        }
+        let now = SystemTime::now();
+        let user = self.users.get_user(userid).await;
+        let hash = match self.users.password_hash(userid).await {
+            Some(hash) => hash,
+            None => PasswordHash::unmatchable(self.users.iterations().await),
+        };
+        // PBKDF2 is slow on purpose, so it runs on the blocking pool rather
+        // than holding up every other connection.
+        let password = password.to_owned();
+        let matches = tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .unwrap_or(false);
+
+        let locked = user
+            .as_ref()
+            .and_then(|user| user.locked_for(now))
+            .is_some();
+        if matches && !locked {
+            self.failures = 0;
+            self.retry_at = None;
+            self.users.clear_failures(userid).await?;
+            return Ok(());
+        }
+        self.failures = self.failures.saturating_add(1);
+        self.retry_at = Some(Instant::now() + self.lockout.backoff_for(self.failures));
+        if user.is_some() && !locked {
+            self.users
+                .record_failure(userid, &self.lockout, now)
+                .await?;
+        }
+        Err(Rejection::Credentials)
+    }
+
+    /// Creates `userid` in the registry and the key store. The id has to be
+    /// free in both before either changes; `create_user` then claims it
+    /// against other connections, and is undone if the keychain can't be
+    /// stored.
+    async fn register(
+        &mut self,
+        userid: String,
+        password: String,
+        public_key: &[u8],
+        signing_key: &[u8],
+    ) -> Result<(), Rejection> {
+        let public_key = parse_public_key(public_key)?;
+        let signing_key = parse_signing_key(signing_key)?;
+        if let Some(registrations) = &self.registrations {
+            if !registrations
+                .lock()
+                .unwrap()
+                .allow(&self.source, Instant::now())
+            {
+                return Err(Rejection::TooManyRegistrations);
+            }
+        }
+        if self.users.get_user(&userid).await.is_some() {
+            return Err(RegistryError::AlreadyExists.into());
+        }
+        match self.keys.get_key(&userid).await {
+            Err(KeyStoreError::NotFound) => {}
+            Ok(_) => return Err(KeyStoreError::Conflict.into()),
+            Err(err) => return Err(err.into()),
+        }
+        let mut user = User::new(&userid, &userid);
+        user.public_keys.push(public_key.to_bytes());
+        let iterations = self.users.iterations().await;
+        let password =
+            tokio::task::spawn_blocking(move || PasswordHash::new(&password, iterations))
+                .await
+                .map_err(|_| RegistryError::Store)?;
+        self.users.create_user(user, password).await?;
+        let inserted = self
+            .keys
+            .insert_key(userid.clone(), self.keychain(public_key, signing_key))
+            .await;
+        if let Err(err) = inserted {
+            // Otherwise the id stays taken by a user that can't sign in.
+            self.users.remove_user(&userid).await.ok();
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Makes `userid` this connection's user, shows it online, and passes
+    /// on its mail: what is waiting now, as far as the reply queue has
+    /// room, then whatever arrives while it stays signed in.
+    async fn sign_in(&mut self, userid: &str) {
+        self.set_status(Presence::Offline);
+        let (push_tx, push_rx) = mpsc::unbounded_channel();
+        let waiting = self
+            .mail
+            .lock()
+            .await
+            .subscribe(userid, push_tx.clone(), SystemTime::now());
+        // Whatever doesn't fit stays in the mailbox for the next sign-in.
+        for mail in waiting {
+            if self.out_tx.try_send(mail.to_message(userid)).is_err() {
+                break;
+            }
+        }
+        // Replacing the receiver drops any earlier user's subscriptions.
+        self.push_tx = Some(push_tx);
+        self.push_rx = Some(push_rx);
+        self.session = Some(userid.to_owned());
+        self.set_status(Presence::Online);
+    }
+
+    /// Moves this connection's share of the signed-in user's presence.
+    fn set_status(&mut self, status: Presence) {
+        if let Some(userid) = &self.session {
+            self.presence
+                .lock()
+                .unwrap()
+                .update(userid, self.status, status);
+        }
+        self.status = status;
+    }
+
+    /// Tells the signed-in user where `subject` stands and, with `watch`,
+    /// every change from now on. Only once `subject` has forwarded them
+    /// mail, e.g. to start a key exchange, as a stranger shouldn't learn
+    /// when someone is around.
+    async fn report_presence(
+        &mut self,
+        userid: &str,
+        subject: &str,
+        watch: bool,
+    ) -> Result<(), Rejection> {
+        if self.session.as_deref() != Some(userid) {
+            return Err(Rejection::SignedOut);
+        }
+        if subject != userid && !self.mail.lock().await.has_written(subject, userid) {
+            return Err(Rejection::Hidden);
+        }
+        let mut presence = self.presence.lock().unwrap();
+        let status = match self.push_tx.clone().filter(|_| watch) {
+            Some(push_tx) => presence.watch(subject, userid, push_tx),
+            None => presence.presence(subject),
+        };
+        drop(presence);
+        self.send(SealedMessage::Presence {
+            userid,
+            subject,
+            status: status as u8,
+        });
+        Ok(())
+    }
+
+    /// Queues `message` in `recipient`'s mailbox, provided the sender
+    /// signed it for that recipient and hasn't sent it before.
+    async fn forward(
+        &mut self,
+        userid: &str,
+        recipient: &str,
+        counter: u64,
+        signature: Vec<u8>,
+        message: Vec<u8>,
+    ) -> Result<(), Rejection> {
+        self.users.check_active(userid).await?;
+        let key = self.keys.get_key(&userid.to_owned()).await?;
+        if key.is_expired(SystemTime::now()) {
+            return Err(Rejection::Expired);
+        }
+        let header = SealedMessage::Forward {
+            userid,
+            recipient,
+            counter,
+            signature: &[],
+            message: &[],
+        }
+        .header();
+        if !key.verify(&[header.as_slice(), &message].concat(), &signature) {
+            return Err(Rejection::BadSignature);
+        }
+        self.seen
+            .lock()
+            .await
+            .counter(userid, key.version, counter)
+            .await?;
+
+        self.users.check_active(recipient).await?;
+        self.mail
+            .lock()
+            .await
+            .deposit(
+                recipient,
+                userid,
+                counter,
+                signature,
+                message,
+                SystemTime::now(),
             )
-            .unwrap();
+            .await?;
+        Ok(())
+    }
+
+    /// Drops mail the signed-in user has received.
+    async fn collect(&mut self, userid: &str, id: u64) -> Result<(), Rejection> {
+        if self.session.as_deref() != Some(userid) {
+            return Err(Rejection::SignedOut);
+        }
+        self.mail.lock().await.acknowledge(userid, id).await?;
+        Ok(())
+    }
+
+    /// Takes a new signed prekey and more one-time prekeys, provided the
+    /// user's signing key vouches for them.
+    async fn publish_prekeys(
+        &mut self,
+        userid: &str,
+        signed_prekey: Vec<u8>,
+        prekey_signature: Vec<u8>,
+        one_time_prekeys: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Rejection> {
+        self.users.check_active(userid).await?;
+        let key = self.keys.get_key(&userid.to_owned()).await?;
+        if key.is_expired(SystemTime::now()) {
+            return Err(Rejection::Expired);
+        }
+        let signed = [signed_prekey.as_slice(), one_time_prekeys].concat();
+        if !key.verify(&signed_prekey, &prekey_signature) || !key.verify(&signed, signature) {
+            return Err(Rejection::BadSignature);
+        }
+
+        let signed_prekey = signed_prekey
+            .try_into()
+            .map_err(|_| PreKeyError::Malformed)?;
+        self.prekeys
+            .lock()
+            .await
+            .publish(userid, signed_prekey, prekey_signature, one_time_prekeys)
+            .await?;
+        Ok(())
+    }
+
+    /// Sends the signed-in user a bundle for starting a session with
+    /// `subject`, using up one of its one-time prekeys.
+    async fn fetch_prekeys(&mut self, userid: &str, subject: &str) -> Result<(), Rejection> {
+        if self.session.as_deref() != Some(userid) {
+            return Err(Rejection::SignedOut);
+        }
+        self.prekeys
+            .lock()
+            .await
+            .allow_fetch(userid, Instant::now())?;
+
+        self.users.check_active(subject).await?;
+        let key = self.keys.get_key(&subject.to_owned()).await?;
+        if key.is_expired(SystemTime::now()) {
+            return Err(Rejection::Expired);
+        }
+        // Prekeys are only taken from users with a signing key.
+        let signing_key = key.signing_key.ok_or(PreKeyError::NotFound)?;
+
+        let bundle = self.prekeys.lock().await.take_bundle(subject).await?;
+        self.send(SealedMessage::PreKeyBundle {
+            userid,
+            subject,
+            identity_key: key.public_key.as_bytes(),
+            signing_key: &signing_key,
+            signed_prekey: &bundle.signed_prekey,
+            prekey_signature: &bundle.prekey_signature,
+            one_time_prekey: bundle
+                .one_time_prekey
+                .as_ref()
+                .map_or(&[], |prekey| prekey.as_slice()),
+        });
+        Ok(())
+    }
+
+    /// Asks `userid` for more one-time prekeys if it is running low.
+    async fn check_prekeys(&self, userid: &str) {
+        let remaining = {
+            let prekeys = self.prekeys.lock().await;
+            if !prekeys.is_low(userid) {
+                return;
+            }
+            prekeys.remaining(userid).unwrap_or(0)
+        };
+        self.send(SealedMessage::PreKeysLow {
+            userid,
+            remaining: remaining as u32,
+        });
     }
 
-    fn communicate(
+    fn keychain(
+        &self,
+        public_key: PublicKey,
+        signing_key: Option<[u8; ED25519_PUBLIC_KEY_LEN]>,
+    ) -> ForeignKeychain {
+        let keychain = ForeignKeychain::new(public_key, self.guard_key.clone());
+        let keychain = match signing_key {
+            Some(signing_key) => keychain.with_signing_key(signing_key),
+            None => keychain,
+        };
+        match self.key_lifetime {
+            Some(lifetime) => keychain.with_lifetime(lifetime),
+            None => keychain,
+        }
+    }
+
+    async fn communicate(
         &mut self,
-        userid: &'a str,
-        message: &'a [u8],
-        signature: &'a [u8],
+        userid: String,
+        counter: u64,
+        message: Vec<u8>,
+        signature: Vec<u8>,
     ) -> DecodedMessage {
-        let key = self.keys.get_key(userid).unwrap(); // TODO: Remove this
-        DecodedMessage {
-            encryption_data: EncryptionData::Passed {
+        let failed = |target, message| DecodedMessage {
+            encryption_data: EncryptionData::Failed {
+                target,
                 encrypted: false,
+            },
+            message,
+        };
+        if self.users.check_active(&userid).await.is_err() {
+            return failed(userid, message);
+        }
+
+        let header = SealedMessage::Communicate {
+            userid: &userid,
+            counter,
+            signature: &[],
+            message: &[],
+        }
+        .header();
+        let signed = [header.as_slice(), &message].concat();
+        // Messages signed just before a `Sync` may still arrive under the key
+        // it replaced, which the store keeps for a grace period.
+        let key = match self.keys.get_key(&userid).await {
+            Ok(key) if key.verify(&signed, &signature) => key,
+            Ok(_) => match self.keys.get_previous_key(&userid).await {
+                Ok(key) if key.verify(&signed, &signature) => key,
+                _ => return failed(userid, message),
+            },
+            Err(_) => return failed(userid, message),
+        };
+
+        if key.is_expired(SystemTime::now()) {
+            return DecodedMessage {
+                encryption_data: EncryptionData::Expired {
+                    username: userid,
+                    version: key.version,
+                },
+                message,
+            };
+        }
+        let seen = self
+            .seen
+            .lock()
+            .await
+            .counter(&userid, key.version, counter)
+            .await;
+        match seen {
+            Ok(()) => {}
+            Err(ReplayError::Seen(error)) => {
+                return DecodedMessage {
+                    encryption_data: EncryptionData::Replayed {
+                        username: userid,
+                        error,
+                    },
+                    message,
+                }
+            }
+            Err(ReplayError::Store(_)) => return failed(userid, message),
+        }
+        DecodedMessage {
+            encryption_data: EncryptionData::Passed { username: userid },
+            message,
+        }
+    }
+
+    async fn red_box(&mut self, userid: String, sent_at: u64, message: Vec<u8>) -> DecodedMessage {
+        let header = SealedMessage::RedBox {
+            userid: &userid,
+            sent_at,
+            message: &[],
+        }
+        .header();
+        let opened = match red_box::open(&self.guard_key, &header, &message) {
+            Ok(opened) => opened,
+            Err(_) => {
+                return DecodedMessage {
+                    encryption_data: EncryptionData::Failed {
+                        target: userid,
+                        encrypted: true,
+                    },
+                    message,
+                }
+            }
+        };
+
+        // A box that opened starts with the one-time key it was sealed with.
+        let ephemeral: [u8; 32] = message[..32].try_into().unwrap();
+        let seen = self
+            .seen
+            .lock()
+            .await
+            .red_box(ephemeral, sent_at, SystemTime::now());
+        let encryption_data = match seen {
+            Ok(()) => EncryptionData::Anonymous { username: userid },
+            Err(error) => EncryptionData::Replayed {
                 username: userid,
-                sharedkey: &key.shared_key,
+                error,
             },
-            message: message,
+        };
+        DecodedMessage {
+            encryption_data,
+            message: opened,
+        }
+    }
+}
+
+impl<KS: KeyStore<ID = String>, US: CredentialStore + UserRegistry> Drop for SocketGuard<KS, US> {
+    fn drop(&mut self) {
+        if let (Some(userid), Ok(mut presence)) = (&self.session, self.presence.lock()) {
+            presence.update(userid, self.status, Presence::Offline);
         }
     }
+}
+
+/// Never completes without a deadline.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Whole seconds, rounded up so that a client never retries too early.
+fn seconds(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// Reads a client's X25519 key. Only 32 bytes will do, and none of the
+/// points of small order.
+fn parse_public_key(bytes: &[u8]) -> Result<PublicKey, Rejection> {
+    let point: [u8; 32] = bytes.try_into().map_err(|_| Rejection::BadKey)?;
+    let key = PublicKey::from(point);
+    if keys::is_low_order(&key) {
+        return Err(Rejection::BadKey);
+    }
+    Ok(key)
+}
 
-    fn red_box(&mut self, userid: &'a str, message: &'a [u8]) -> DecodedMessage {
-        todo!()
+/// Reads a client's Ed25519 key. Empty means it has none.
+fn parse_signing_key(bytes: &[u8]) -> Result<Option<[u8; ED25519_PUBLIC_KEY_LEN]>, Rejection> {
+    match bytes {
+        [] => Ok(None),
+        bytes => bytes.try_into().map(Some).map_err(|_| Rejection::BadKey),
     }
 }
 
 #[tokio::main]
 async fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match server::Config::from_env() {
+        Ok(config) => match args.as_slice() {
+            [command, action, id] if command == "user" => {
+                server::manage_user(&config, action, id).await
+            }
+            _ => server::run(config).await,
+        },
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = result {
+        eprintln!("idms: {}", err);
+        std::process::exit(1);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::num::NonZeroU32;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::time::{Duration, SystemTime};
 
+    use idms::secure::sym::DecryptError;
+    use ring::hmac;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
     use x25519_dalek::{PublicKey, StaticSecret};
 
+    use crate::limit::RateLimit;
+    use crate::mailbox::MailStore;
+    use crate::presence::{KeepalivePolicy, Presence};
+    use crate::security::KeyStoreError;
+    use crate::security::{
+        resume_proof_key, resumed_key, resumption_secret, OwnedSealedMessage, SealedMessage,
+        RESUME_NONCE_LEN,
+    };
+    use crate::ticket::TicketError;
+    use crate::user::{CredentialStore, LockoutPolicy, UserRegistry, UserStore};
     use crate::{
-        DecodedMessage, EncryptionData, ForeignKeychain, KeyStore, SealedMessage, SocketGuard,
+        parse_public_key, DecodedMessage, EncryptionData, ForeignKeychain, KeyStore, SocketGuard,
+        REGISTRATION_WINDOW, REPLY_QUEUE,
     };
 
     #[derive(Default)]
-    struct TestKs<'a> {
-        keys: HashMap<&'a str, ForeignKeychain>,
+    struct TestKs {
+        keys: HashMap<String, ForeignKeychain>,
     }
 
-    impl<'a> KeyStore for TestKs<'a> {
-        type ID = &'a str;
+    impl KeyStore for TestKs {
+        type ID = String;
 
-        fn set_key(
+        async fn set_key(
             &mut self,
             id: Self::ID,
             keychain: ForeignKeychain,
-        ) -> Result<Option<ForeignKeychain>, ()> {
+        ) -> Result<Option<ForeignKeychain>, KeyStoreError> {
             let kc = self.keys.insert(id, keychain);
             Ok(kc)
         }
 
-        fn get_key(&mut self, id: Self::ID) -> Option<&ForeignKeychain> {
-            self.keys.get(id)
+        async fn get_key(&self, id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError> {
+            self.keys.get(id).cloned().ok_or(KeyStoreError::NotFound)
         }
     }
 
-    const EXAMPLE_PUBLIC_KEY_BYTES: &'static [u8; 32] = &[0u8; 32];
-    const EXAMPLE_STATIC_KEY_BYTES: &'static [u8; 32] = &[1u8; 32];
-    const TEST_USERNAME: &'static str = "TEST_USERNAME";
-    const TEST_PASSWORD: &'static str = "TEST_PASSWORD";
-    const TEST_MESSAGE: &'static [u8] = b"Hello World";
+    const EXAMPLE_PUBLIC_KEY_BYTES: &[u8; 32] = &[9u8; 32];
+    const EXAMPLE_STATIC_KEY_BYTES: &[u8; 32] = &[1u8; 32];
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const TEST_PASSWORD: &str = "TEST_PASSWORD";
+    const TEST_MESSAGE: &[u8] = b"Hello World";
 
-    #[test]
-    fn example_keystore() {
+    fn test_users() -> UserStore {
+        UserStore::with_iterations(NonZeroU32::new(1).unwrap())
+    }
+
+    fn test_identity() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[3u8; 32]).unwrap()
+    }
+
+    /// What a `Communicate` from `TEST_USERNAME` carrying `message` at
+    /// counter 0 has to be signed over.
+    fn communicate_signed(message: &[u8]) -> Vec<u8> {
+        let header = SealedMessage::Communicate {
+            userid: TEST_USERNAME,
+            counter: 0,
+            signature: &[],
+            message: &[],
+        }
+        .header();
+        [header.as_slice(), message].concat()
+    }
+
+    #[tokio::test]
+    async fn example_keystore() {
         let mut ks = TestKs::default();
 
         ks.set_key(
-            TEST_USERNAME,
+            TEST_USERNAME.to_owned(),
             ForeignKeychain::new(
                 PublicKey::from(*EXAMPLE_PUBLIC_KEY_BYTES),
                 StaticSecret::from(*EXAMPLE_STATIC_KEY_BYTES),
             ),
         )
+        .await
         .unwrap();
 
         assert_eq!(
-            ks.get_key(TEST_USERNAME).unwrap().public_key.as_bytes(),
+            ks.get_key(&TEST_USERNAME.to_owned())
+                .await
+                .unwrap()
+                .public_key
+                .as_bytes(),
             EXAMPLE_PUBLIC_KEY_BYTES
         );
 
         let old = ks
             .set_key(
-                TEST_USERNAME,
+                TEST_USERNAME.to_owned(),
                 ForeignKeychain::new(
                     PublicKey::from(*EXAMPLE_STATIC_KEY_BYTES),
                     StaticSecret::from(*EXAMPLE_STATIC_KEY_BYTES),
                 ),
             )
+            .await
             .unwrap();
 
         assert_eq!(old.unwrap().public_key.as_bytes(), EXAMPLE_PUBLIC_KEY_BYTES);
         assert_eq!(
-            ks.get_key(TEST_USERNAME).unwrap().public_key.as_bytes(),
+            ks.get_key(&TEST_USERNAME.to_owned())
+                .await
+                .unwrap()
+                .public_key
+                .as_bytes(),
             EXAMPLE_STATIC_KEY_BYTES
         );
+
+        assert!(matches!(
+            ks.insert_key(
+                TEST_USERNAME.to_owned(),
+                ForeignKeychain::new(
+                    PublicKey::from(*EXAMPLE_PUBLIC_KEY_BYTES),
+                    StaticSecret::from(*EXAMPLE_STATIC_KEY_BYTES),
+                ),
+            )
+            .await,
+            Err(KeyStoreError::Conflict)
+        ));
+        assert!(matches!(
+            ks.get_key(&"UNKNOWN".to_owned()).await,
+            Err(KeyStoreError::NotFound)
+        ));
     }
 
     #[tokio::test]
     async fn socket_guard_sync() {
         use tokio::sync::mpsc;
-        let (tx, mut rx) = mpsc::channel(1);
+        let (tx, rx) = mpsc::channel(1);
 
-        let mut guard = SocketGuard::new(rx, TestKs::default());
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
         tokio::spawn(async move {
             guard.next().await;
         });
 
-        tx.send(SealedMessage::Sync {
-            userid: TEST_USERNAME,
-            password: TEST_PASSWORD,
-            public_key: EXAMPLE_PUBLIC_KEY_BYTES,
-        })
+        tx.send(
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+                revoke: false,
+            }
+            .into(),
+        )
         .await
         .unwrap();
     }
 
     #[tokio::test]
+    #[allow(clippy::match_like_matches_macro)]
     async fn socket_guard_communicate() {
         use tokio::sync::mpsc;
         let (tx, rx) = mpsc::channel(1);
 
-        tx.send(SealedMessage::Sync {
-            userid: TEST_USERNAME,
-            password: TEST_PASSWORD,
-            public_key: EXAMPLE_PUBLIC_KEY_BYTES,
-        })
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            }
+            .into(),
+        )
         .await
         .unwrap();
-        let mut guard = SocketGuard::new(rx, TestKs::default());
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
 
         assert!(guard.next().await.is_none());
 
-        tx.send(SealedMessage::Communicate {
-            userid: TEST_USERNAME,
-            signature: &[0u8; 32],
-            message: TEST_MESSAGE,
-        })
+        tx.send(
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: test_identity()
+                    .sign(&communicate_signed(TEST_MESSAGE))
+                    .as_ref(),
+                message: TEST_MESSAGE,
+            }
+            .into(),
+        )
         .await
         .unwrap();
 
         let nxt: DecodedMessage = guard.next().await.unwrap();
 
         assert!(match nxt.encryption_data {
-            EncryptionData::Passed {
-                encrypted: _,
-                username: _,
-                sharedkey: _,
-            } => true,
+            EncryptionData::Passed { username: _ } => true,
             _ => false,
         });
 
         println!("{:?}", nxt.message);
     }
+
+    #[tokio::test]
+    async fn socket_guard_flags_expired_keys() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard =
+            SocketGuard::new(rx, TestKs::default(), test_users()).with_key_lifetime(Duration::ZERO);
+        let identity = test_identity();
+        let signature = identity.sign(&communicate_signed(TEST_MESSAGE));
+        let communicate: OwnedSealedMessage = SealedMessage::Communicate {
+            userid: TEST_USERNAME,
+            counter: 0,
+            signature: signature.as_ref(),
+            message: TEST_MESSAGE,
+        }
+        .into();
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+
+        tx.send(communicate.clone()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Expired { version: 1, .. }
+        ));
+
+        // Re-syncing rotates in a fresh keychain.
+        guard.key_lifetime = None;
+        tx.send(
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_STATIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+                revoke: false,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+        assert_eq!(
+            guard
+                .keys
+                .get_key(&TEST_USERNAME.to_owned())
+                .await
+                .unwrap()
+                .version,
+            2
+        );
+
+        tx.send(communicate).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_replies() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let mut replies = guard.replies().unwrap();
+        assert!(guard.replies().is_none());
+
+        let client_key = StaticSecret::from(*EXAMPLE_STATIC_KEY_BYTES);
+        let identity = test_identity();
+        let signature = identity.sign(&communicate_signed(TEST_MESSAGE));
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: PublicKey::from(&client_key).as_bytes(),
+                signing_key: identity.public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ack {
+                userid: TEST_USERNAME.to_owned()
+            }
+        );
+
+        tx.send(
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: signature.as_ref(),
+                message: b"tampered",
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_lets_go_of_clients_that_stop_reading() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let mut replies = guard.replies().unwrap();
+
+        // Nothing is dropped while there is room.
+        for _ in 0..REPLY_QUEUE {
+            tx.send(OwnedSealedMessage::Nil).await.unwrap();
+            guard.next().await;
+        }
+        tx.send(OwnedSealedMessage::Nil).await.unwrap();
+        guard.next().await;
+        tx.send(OwnedSealedMessage::Nil).await.unwrap();
+        assert!(guard.recv().await.is_none());
+
+        drop(guard);
+        let mut queued = 0;
+        while let Some(reply) = replies.recv().await {
+            assert_eq!(reply, OwnedSealedMessage::Nil);
+            queued += 1;
+        }
+        assert_eq!(queued, REPLY_QUEUE);
+    }
+
+    #[tokio::test]
+    async fn socket_guard_sync_checks_password() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users()).with_lockout_policy(
+            LockoutPolicy {
+                backoff: Duration::ZERO,
+                ..LockoutPolicy::default()
+            },
+        );
+        let identity = test_identity();
+        let intruder = Ed25519KeyPair::from_seed_unchecked(&[4u8; 32]).unwrap();
+
+        let messages = [
+            // Re-keying someone who never registered does nothing.
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: intruder.public_key().as_ref(),
+                revoke: false,
+            },
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+            },
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: "ANOTHER_PASSWORD",
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: intruder.public_key().as_ref(),
+            },
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: "WRONG_PASSWORD",
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: intruder.public_key().as_ref(),
+                revoke: false,
+            },
+        ];
+        for message in messages {
+            tx.send(message.into()).await.unwrap();
+            assert!(guard.next().await.is_none());
+        }
+
+        // The registered key survived every attempt to replace it.
+        tx.send(
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: test_identity()
+                    .sign(&communicate_signed(TEST_MESSAGE))
+                    .as_ref(),
+                message: TEST_MESSAGE,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+
+        tx.send(
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: intruder.public_key().as_ref(),
+                revoke: false,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+
+        tx.send(
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: intruder.sign(&communicate_signed(TEST_MESSAGE)).as_ref(),
+                message: TEST_MESSAGE,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+    }
+
+    #[test]
+    fn public_keys_must_be_whole_and_strong() {
+        assert!(parse_public_key(EXAMPLE_PUBLIC_KEY_BYTES).is_ok());
+        assert!(parse_public_key(&EXAMPLE_PUBLIC_KEY_BYTES[..31]).is_err());
+        assert!(parse_public_key(&[9u8; 33]).is_err());
+        assert!(parse_public_key(&[0u8; 32]).is_err());
+        // p + 1 with the ignored top bit set is still the point 1.
+        let mut one = [0xffu8; 32];
+        one[0] = 0xee;
+        assert!(parse_public_key(&one).is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_guard_register_claims_both_stores_or_neither() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut ks = TestKs::default();
+        // A keychain with no user behind it.
+        ks.set_key(
+            TEST_USERNAME.to_owned(),
+            ForeignKeychain::new(
+                PublicKey::from(*EXAMPLE_PUBLIC_KEY_BYTES),
+                StaticSecret::from(*EXAMPLE_STATIC_KEY_BYTES),
+            ),
+        )
+        .await
+        .unwrap();
+        let mut guard = SocketGuard::new(rx, ks, test_users());
+        let mut replies = guard.replies().unwrap();
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        assert!(guard.users.get_user(TEST_USERNAME).await.is_none());
+        assert!(guard.users.password_hash(TEST_USERNAME).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn socket_guard_locks_out_password_guessing() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users()).with_lockout_policy(
+            LockoutPolicy {
+                max_failures: 2,
+                backoff: Duration::ZERO,
+                ..LockoutPolicy::default()
+            },
+        );
+        let mut replies = guard.replies().unwrap();
+        let identity = test_identity();
+        let sync = |password| -> OwnedSealedMessage {
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+                revoke: false,
+            }
+            .into()
+        };
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        replies.recv().await.unwrap();
+
+        for password in ["GUESS_1", "GUESS_2", TEST_PASSWORD] {
+            tx.send(sync(password)).await.unwrap();
+            guard.next().await;
+        }
+        let wrong_password = replies.recv().await.unwrap();
+        assert!(matches!(wrong_password, OwnedSealedMessage::Reject { .. }));
+        // The second wrong guess locks the account, and then even the right
+        // password is refused, in the same words so that the lock gives
+        // nothing away.
+        for _ in 0..2 {
+            assert_eq!(replies.recv().await.unwrap(), wrong_password);
+        }
+        assert_eq!(
+            guard
+                .users_mut()
+                .get_user(TEST_USERNAME)
+                .await
+                .unwrap()
+                .failed_logins,
+            2
+        );
+
+        // An unknown id is turned down in the same words as a wrong
+        // password.
+        tx.send(
+            SealedMessage::Sync {
+                userid: "NOBODY",
+                password: "GUESS_3",
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+                revoke: false,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        match (wrong_password, replies.recv().await.unwrap()) {
+            (
+                OwnedSealedMessage::Reject { reason, .. },
+                OwnedSealedMessage::Reject {
+                    reason: unknown_user,
+                    ..
+                },
+            ) => assert_eq!(reason, unknown_user),
+            other => panic!("expected two rejections, got {:?}", other),
+        }
+
+        // A connection that keeps guessing has to wait between tries, even
+        // for ids that don't exist.
+        guard.lockout.backoff = Duration::from_secs(10);
+        tx.send(
+            SealedMessage::Sync {
+                userid: "NOBODY",
+                password: "GUESS_4",
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+                revoke: false,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        // That was the connection's fourth failure: 10s doubled three
+        // times, then capped by `max_backoff`.
+        tx.send(sync(TEST_PASSWORD)).await.unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Locked { seconds: 30, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_refuses_inactive_users() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let identity = test_identity();
+        let signature = identity.sign(&communicate_signed(TEST_MESSAGE));
+
+        let register = SealedMessage::Register {
+            userid: TEST_USERNAME,
+            password: TEST_PASSWORD,
+            public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+            signing_key: identity.public_key().as_ref(),
+        };
+        let communicate = SealedMessage::Communicate {
+            userid: TEST_USERNAME,
+            counter: 0,
+            signature: signature.as_ref(),
+            message: TEST_MESSAGE,
+        };
+
+        tx.send(register.into()).await.unwrap();
+        assert!(guard.next().await.is_none());
+        assert!(guard.users_mut().check_active(TEST_USERNAME).await.is_ok());
+
+        guard.users_mut().disable_user(TEST_USERNAME).await.unwrap();
+        tx.send(communicate.into()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Failed { .. }
+        ));
+
+        guard.users_mut().enable_user(TEST_USERNAME).await.unwrap();
+        tx.send(communicate.into()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+
+        guard.users_mut().delete_user(TEST_USERNAME).await.unwrap();
+        tx.send(communicate.into()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Failed { .. }
+        ));
+
+        // A deleted id stays taken.
+        tx.send(register.into()).await.unwrap();
+        assert!(guard.next().await.is_none());
+        assert!(guard.users_mut().check_active(TEST_USERNAME).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_guard_turns_away_replays() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let seen = Arc::default();
+        let mut guard =
+            SocketGuard::new(rx, TestKs::default(), test_users()).with_seen(Arc::clone(&seen));
+        let mut replies = guard.replies().unwrap();
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+        while replies.try_recv().is_ok() {}
+
+        let signature = test_identity().sign(&communicate_signed(TEST_MESSAGE));
+        let communicate: OwnedSealedMessage = SealedMessage::Communicate {
+            userid: TEST_USERNAME,
+            counter: 0,
+            signature: signature.as_ref(),
+            message: TEST_MESSAGE,
+        }
+        .into();
+        tx.send(communicate.clone()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+
+        tx.send(communicate.clone()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Replayed {
+                error: DecryptError::Replayed,
+                ..
+            }
+        ));
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+
+        // Nor does another connection let it through.
+        let (tx, rx) = mpsc::channel(1);
+        let keys = std::mem::take(&mut guard.keys);
+        let users = std::mem::replace(guard.users_mut(), test_users());
+        let mut other = SocketGuard::new(rx, keys, users).with_seen(seen);
+        tx.send(communicate).await.unwrap();
+        assert!(matches!(
+            other.next().await.unwrap().encryption_data,
+            EncryptionData::Replayed { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_rejects_bad_signatures() {
+        use tokio::sync::mpsc;
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let mut replies = guard.replies().unwrap();
+
+        // Nobody has synced yet, so there is no key to check against.
+        tx.send(
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: test_identity()
+                    .sign(&communicate_signed(TEST_MESSAGE))
+                    .as_ref(),
+                message: TEST_MESSAGE,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Failed { .. }
+        ));
+        let unknown_user = replies.recv().await.unwrap();
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+        replies.recv().await.unwrap();
+
+        tx.send(
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: test_identity()
+                    .sign(&communicate_signed(b"Goodbye World"))
+                    .as_ref(),
+                message: TEST_MESSAGE,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Failed { .. }
+        ));
+        // A forged signature and an unknown sender read the same.
+        let bad_signature = replies.recv().await.unwrap();
+        assert!(matches!(bad_signature, OwnedSealedMessage::Reject { .. }));
+        assert_eq!(bad_signature, unknown_user);
+    }
+
+    #[tokio::test]
+    async fn socket_guard_revokes_the_replaced_key_on_request() {
+        use crate::store::FileKeyStore;
+        use crate::testing::TempPath;
+        use tokio::sync::mpsc;
+
+        let path = TempPath::new("revoke");
+        let keys = FileKeyStore::open(&path, &[5u8; 32]).unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, keys, test_users());
+        let identities: Vec<Ed25519KeyPair> = (1..=3)
+            .map(|seed| Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap())
+            .collect();
+        let communicate = |identity: &Ed25519KeyPair| -> OwnedSealedMessage {
+            SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: identity.sign(&communicate_signed(TEST_MESSAGE)).as_ref(),
+                message: TEST_MESSAGE,
+            }
+            .into()
+        };
+        let sync = |identity: &Ed25519KeyPair, revoke| -> OwnedSealedMessage {
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+                revoke,
+            }
+            .into()
+        };
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identities[0].public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        assert!(guard.next().await.is_none());
+
+        // Without `revoke`, the replaced key keeps verifying for a while.
+        tx.send(sync(&identities[1], false)).await.unwrap();
+        assert!(guard.next().await.is_none());
+        tx.send(communicate(&identities[0])).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+
+        // With it, the replaced key stops at once.
+        tx.send(sync(&identities[2], true)).await.unwrap();
+        assert!(guard.next().await.is_none());
+        tx.send(communicate(&identities[1])).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Failed { .. }
+        ));
+        tx.send(communicate(&identities[2])).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Passed { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_limits_registrations_per_source() {
+        use tokio::sync::mpsc;
+        let registrations = Arc::new(StdMutex::new(RateLimit::new(1, REGISTRATION_WINDOW)));
+        let register = |userid| -> OwnedSealedMessage {
+            SealedMessage::Register {
+                userid,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: &[],
+            }
+            .into()
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users())
+            .with_registrations(Arc::clone(&registrations))
+            .with_source("192.0.2.1".to_owned());
+        let mut replies = guard.replies().unwrap();
+        tx.send(register(TEST_USERNAME)).await.unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ack { .. }
+        ));
+        tx.send(register("ANOTHER_USER")).await.unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        assert!(guard.users.get_user("ANOTHER_USER").await.is_none());
+
+        // Another source has a count of its own.
+        let (tx, rx) = mpsc::channel(1);
+        let mut other = SocketGuard::new(rx, TestKs::default(), test_users())
+            .with_registrations(registrations)
+            .with_source("192.0.2.2".to_owned());
+        let mut replies = other.replies().unwrap();
+        tx.send(register("ANOTHER_USER")).await.unwrap();
+        other.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ack { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_frees_the_id_when_registration_fails() {
+        use crate::store::FileKeyStore;
+        use crate::testing::TempPath;
+        use tokio::sync::mpsc;
+
+        // Keychains can't be written under a directory that doesn't exist.
+        let dir = TempPath::new("no-keys");
+        let keys = FileKeyStore::open(dir.join("keys.db"), &[5u8; 32]).unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, keys, test_users());
+        let mut replies = guard.replies().unwrap();
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: &[],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        assert!(guard.users_mut().get_user(TEST_USERNAME).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn socket_guard_forwards_mail() {
+        use tokio::sync::mpsc;
+
+        const SENDER: &str = "SENDER";
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let mut replies = guard.replies().unwrap();
+        let identity = test_identity();
+
+        // The recipient registers, then the sender signs in after it.
+        for userid in [TEST_USERNAME, SENDER] {
+            tx.send(
+                SealedMessage::Register {
+                    userid,
+                    password: TEST_PASSWORD,
+                    public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                    signing_key: identity.public_key().as_ref(),
+                }
+                .into(),
+            )
+            .await
+            .unwrap();
+            guard.next().await;
+            replies.recv().await.unwrap();
+        }
+
+        let sign = |counter| {
+            let header = SealedMessage::Forward {
+                userid: SENDER,
+                recipient: TEST_USERNAME,
+                counter,
+                signature: &[],
+                message: &[],
+            }
+            .header();
+            identity.sign(&[header.as_slice(), TEST_MESSAGE].concat())
+        };
+        let forward = |counter, signature: &[u8]| -> OwnedSealedMessage {
+            SealedMessage::Forward {
+                userid: SENDER,
+                recipient: TEST_USERNAME,
+                counter,
+                signature,
+                message: TEST_MESSAGE,
+            }
+            .into()
+        };
+        let mail_ack = |id| -> OwnedSealedMessage {
+            SealedMessage::MailAck {
+                userid: TEST_USERNAME,
+                id,
+            }
+            .into()
+        };
+        let ack = OwnedSealedMessage::Ack {
+            userid: SENDER.to_owned(),
+        };
+        let mail = |id, counter| OwnedSealedMessage::Mail {
+            userid: TEST_USERNAME.to_owned(),
+            id,
+            sender: SENDER.to_owned(),
+            counter,
+            signature: sign(counter).as_ref().to_vec(),
+            message: TEST_MESSAGE.to_vec(),
+        };
+
+        // Only messages the sender signed for this recipient are queued, and
+        // only once.
+        for (signature, reply) in [
+            (identity.sign(b"other"), false),
+            (sign(0), true),
+            (sign(0), false),
+        ] {
+            tx.send(forward(0, signature.as_ref())).await.unwrap();
+            guard.next().await;
+            let got = replies.recv().await.unwrap();
+            assert_eq!(got == ack, reply);
+        }
+
+        // Mail waits for the recipient, and only they can collect it.
+        tx.send(mail_ack(0)).await.unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        tx.send(
+            SealedMessage::Sync {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: identity.public_key().as_ref(),
+                revoke: false,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ack { .. }
+        ));
+        assert_eq!(replies.recv().await.unwrap(), mail(0, 0));
+
+        // Once signed in, new mail is pushed as it arrives.
+        tx.send(forward(1, sign(1).as_ref())).await.unwrap();
+        guard.next().await;
+        assert_eq!(replies.recv().await.unwrap(), ack);
+        tx.send(mail_ack(0)).await.unwrap();
+        guard.next().await;
+        assert_eq!(replies.recv().await.unwrap(), mail(1, 1));
+
+        // Acknowledged mail is gone.
+        tx.send(mail_ack(0)).await.unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socket_guard_tracks_presence() {
+        use tokio::sync::{mpsc, Mutex};
+
+        const WATCHER: &str = "WATCHER";
+        let presence = Arc::default();
+        let mail = Arc::new(Mutex::new(MailStore::default()));
+        let (watcher_tx, rx) = mpsc::channel(1);
+        let mut watcher = SocketGuard::new(rx, TestKs::default(), test_users())
+            .with_presence(Arc::clone(&presence))
+            .with_mail(Arc::clone(&mail));
+        let mut watcher_replies = watcher.replies().unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users())
+            .with_presence(presence)
+            .with_keepalive(KeepalivePolicy {
+                idle_after: Duration::from_millis(20),
+                timeout: Duration::from_millis(60),
+            });
+        let mut replies = guard.replies().unwrap();
+        let register = |userid| -> OwnedSealedMessage {
+            SealedMessage::Register {
+                userid,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            }
+            .into()
+        };
+        let watch = |subject| -> OwnedSealedMessage {
+            SealedMessage::WatchPresence {
+                userid: WATCHER,
+                subject,
+            }
+            .into()
+        };
+        let presence = |status: Presence| OwnedSealedMessage::Presence {
+            userid: WATCHER.to_owned(),
+            subject: TEST_USERNAME.to_owned(),
+            status: status as u8,
+        };
+
+        // Only users signed in on the connection may watch, and only those
+        // that have written to them.
+        mail.lock()
+            .await
+            .deposit(WATCHER, TEST_USERNAME, 0, vec![], vec![], SystemTime::now())
+            .await
+            .unwrap();
+        for message in [
+            watch(TEST_USERNAME),
+            register(WATCHER),
+            watch("STRANGER"),
+            watch(TEST_USERNAME),
+        ] {
+            watcher_tx.send(message).await.unwrap();
+            watcher.next().await;
+        }
+        assert!(matches!(
+            watcher_replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        for _ in 0..2 {
+            // The Ack, then the mail waiting for the watcher.
+            watcher_replies.recv().await.unwrap();
+        }
+        assert!(matches!(
+            watcher_replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        assert_eq!(
+            watcher_replies.recv().await.unwrap(),
+            presence(Presence::Offline)
+        );
+
+        // Heartbeats are answered, then silence idles and closes the
+        // connection.
+        for message in [register(TEST_USERNAME), OwnedSealedMessage::Nil] {
+            tx.send(message).await.unwrap();
+            guard.next().await;
+        }
+        replies.recv().await.unwrap();
+        assert_eq!(replies.recv().await.unwrap(), OwnedSealedMessage::Nil);
+        assert!(guard.next().await.is_none());
+
+        watcher_tx.send(OwnedSealedMessage::Nil).await.unwrap();
+        watcher.next().await;
+        for status in [Presence::Online, Presence::Idle, Presence::Offline] {
+            assert_eq!(watcher_replies.recv().await.unwrap(), presence(status));
+        }
+        assert_eq!(
+            watcher_replies.recv().await.unwrap(),
+            OwnedSealedMessage::Nil
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_guard_resumes_with_ticket() {
+        use tokio::sync::mpsc;
+
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard =
+            SocketGuard::new(rx, TestKs::default(), test_users()).with_tickets(Arc::default());
+        let mut replies = guard.replies().unwrap();
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        replies.recv().await.unwrap();
+        let ticket = match replies.recv().await.unwrap() {
+            OwnedSealedMessage::Ticket { ticket, .. } => ticket,
+            other => panic!("expected a ticket, got {:?}", other),
+        };
+        let keychain = guard.keys.get_key(&TEST_USERNAME.to_owned()).await.unwrap();
+        let secret = resumption_secret(&keychain.shared_key);
+
+        let nonce = [4u8; RESUME_NONCE_LEN];
+        let header = SealedMessage::Resume {
+            userid: TEST_USERNAME,
+            ticket: &[],
+            nonce: &[],
+            proof: &[],
+        }
+        .header();
+        let proof = hmac::sign(
+            &resume_proof_key(&secret),
+            &[header.as_slice(), &ticket, &nonce].concat(),
+        );
+        let resume = |proof: &[u8]| -> OwnedSealedMessage {
+            SealedMessage::Resume {
+                userid: TEST_USERNAME,
+                ticket: &ticket,
+                nonce: &nonce,
+                proof,
+            }
+            .into()
+        };
+
+        // Only the holder of the old shared key can use the ticket, and
+        // only once.
+        for proof in [&[0u8; 32], proof.as_ref()] {
+            tx.send(resume(proof)).await.unwrap();
+            guard.next().await;
+        }
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ack {
+                userid: TEST_USERNAME.to_owned()
+            }
+        );
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ticket { .. }
+        ));
+        let resumed = guard.keys.get_key(&TEST_USERNAME.to_owned()).await.unwrap();
+        assert_eq!(resumed.version, 2);
+        assert_eq!(resumed.shared_key, resumed_key(&secret, &nonce));
+
+        tx.send(resume(proof.as_ref())).await.unwrap();
+        guard.next().await;
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject {
+                userid: TEST_USERNAME.to_owned(),
+                reason: TicketError::Redeemed.to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_guard_hands_out_prekeys() {
+        use idms::secure::x3dh::{self, PreKeyBundle};
+        use tokio::sync::mpsc;
+
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let mut replies = guard.replies().unwrap();
+
+        let identity_key = StaticSecret::from(*EXAMPLE_STATIC_KEY_BYTES);
+        let identity = test_identity();
+
+        // Only for a user signed in on the connection.
+        tx.send(
+            SealedMessage::FetchPreKeys {
+                userid: TEST_USERNAME,
+                subject: TEST_USERNAME,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+
+        tx.send(
+            SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: PublicKey::from(&identity_key).as_bytes(),
+                signing_key: identity.public_key().as_ref(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        guard.next().await;
+        replies.recv().await.unwrap();
+
+        let signed_prekey = StaticSecret::from([4u8; 32]);
+        let one_time_prekeys = [StaticSecret::from([5u8; 32]), StaticSecret::from([6u8; 32])];
+        let signed_public = PublicKey::from(&signed_prekey).to_bytes();
+        let upload: Vec<u8> = one_time_prekeys
+            .iter()
+            .flat_map(|prekey| PublicKey::from(prekey).to_bytes())
+            .collect();
+        let publish = |signature: &[u8]| -> OwnedSealedMessage {
+            SealedMessage::PublishPreKeys {
+                userid: TEST_USERNAME,
+                signed_prekey: &signed_public,
+                prekey_signature: identity.sign(&signed_public).as_ref(),
+                one_time_prekeys: &upload,
+                signature,
+            }
+            .into()
+        };
+
+        // Only uploads signed by the user are taken.
+        tx.send(publish(identity.sign(b"other").as_ref()))
+            .await
+            .unwrap();
+        guard.next().await;
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+
+        let signed = [signed_public.as_slice(), &upload].concat();
+        tx.send(publish(identity.sign(&signed).as_ref()))
+            .await
+            .unwrap();
+        guard.next().await;
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Ack {
+                userid: TEST_USERNAME.to_owned()
+            }
+        );
+        assert_eq!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::PreKeysLow {
+                userid: TEST_USERNAME.to_owned(),
+                remaining: 2
+            }
+        );
+
+        // Each fetch gets a different one-time prekey until they run out.
+        let mut handed_out = Vec::new();
+        for _ in 0..3 {
+            tx.send(
+                SealedMessage::FetchPreKeys {
+                    userid: TEST_USERNAME,
+                    subject: TEST_USERNAME,
+                }
+                .into(),
+            )
+            .await
+            .unwrap();
+            guard.next().await;
+            match replies.recv().await.unwrap() {
+                OwnedSealedMessage::PreKeyBundle {
+                    identity_key,
+                    signing_key,
+                    signed_prekey,
+                    prekey_signature,
+                    one_time_prekey,
+                    ..
+                } => handed_out.push(PreKeyBundle {
+                    identity_key: parse_public_key(&identity_key).unwrap(),
+                    signing_key: signing_key.try_into().unwrap(),
+                    signed_prekey: parse_public_key(&signed_prekey).unwrap(),
+                    prekey_signature,
+                    one_time_prekey: (!one_time_prekey.is_empty())
+                        .then(|| parse_public_key(&one_time_prekey).unwrap()),
+                }),
+                other => panic!("expected a bundle, got {:?}", other),
+            }
+        }
+        assert_ne!(handed_out[0].one_time_prekey, handed_out[1].one_time_prekey);
+        assert!(handed_out[2].one_time_prekey.is_none());
+
+        // The bundle is enough for a sender to agree on a key offline.
+        let sender = StaticSecret::from([8u8; 32]);
+        let (sender_key, ephemeral) = x3dh::initiate(&sender, &handed_out[0]).unwrap();
+        let recipient_key = x3dh::respond(
+            &identity_key,
+            &signed_prekey,
+            Some(&one_time_prekeys[0]),
+            &PublicKey::from(&sender),
+            &ephemeral,
+        );
+        let (mut sent, mut received) = ([0u8; 32], [0u8; 32]);
+        sender_key.export(b"test", &mut sent);
+        recipient_key.export(b"test", &mut received);
+        assert_eq!(sent, received);
+    }
+
+    #[tokio::test]
+    async fn socket_guard_over_stream() {
+        use crate::codec::{FrameReader, FrameWriter};
+        use tokio::sync::mpsc;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (tx, rx) = mpsc::channel(1);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        tokio::spawn(FrameReader::new(server).forward(tx));
+
+        let mut writer = FrameWriter::new(client);
+        writer
+            .write_message(&SealedMessage::Register {
+                userid: TEST_USERNAME,
+                password: TEST_PASSWORD,
+                public_key: EXAMPLE_PUBLIC_KEY_BYTES,
+                signing_key: test_identity().public_key().as_ref(),
+            })
+            .await
+            .unwrap();
+        writer
+            .write_message(&SealedMessage::Communicate {
+                userid: TEST_USERNAME,
+                counter: 0,
+                signature: test_identity()
+                    .sign(&communicate_signed(TEST_MESSAGE))
+                    .as_ref(),
+                message: TEST_MESSAGE,
+            })
+            .await
+            .unwrap();
+
+        assert!(guard.next().await.is_none());
+        let nxt = guard.next().await.unwrap();
+        assert_eq!(nxt.message, TEST_MESSAGE);
+    }
+
+    #[tokio::test]
+    async fn socket_guard_red_box() {
+        use idms::secure::red_box;
+        use tokio::sync::mpsc;
+
+        let (tx, rx) = mpsc::channel(2);
+        let mut guard = SocketGuard::new(rx, TestKs::default(), test_users());
+        let mut replies = guard.replies().unwrap();
+        let sent_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let header = SealedMessage::RedBox {
+            userid: TEST_USERNAME,
+            sent_at,
+            message: &[],
+        }
+        .header();
+        let sealed = red_box::seal(&guard.public_key(), &header, TEST_MESSAGE);
+        tx.send(
+            SealedMessage::RedBox {
+                userid: TEST_USERNAME,
+                sent_at,
+                message: &sealed,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+
+        let nxt = guard.next().await.unwrap();
+        assert!(matches!(
+            nxt.encryption_data,
+            EncryptionData::Anonymous { ref username } if username == TEST_USERNAME
+        ));
+        assert_eq!(nxt.message, TEST_MESSAGE);
+
+        // The claimed sender is bound to the box.
+        tx.send(
+            SealedMessage::RedBox {
+                userid: "SOMEONE_ELSE",
+                sent_at,
+                message: &sealed,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        let nxt = guard.next().await.unwrap();
+        assert!(matches!(nxt.encryption_data, EncryptionData::Failed { .. }));
+
+        // Each box opens once.
+        let replayed = SealedMessage::RedBox {
+            userid: TEST_USERNAME,
+            sent_at,
+            message: &sealed,
+        };
+        tx.send(replayed.into()).await.unwrap();
+        assert!(matches!(
+            guard.next().await.unwrap().encryption_data,
+            EncryptionData::Replayed {
+                error: DecryptError::Replayed,
+                ..
+            }
+        ));
+        assert!(matches!(
+            replies.recv().await.unwrap(),
+            OwnedSealedMessage::Reject { .. }
+        ));
+
+        let sealed = red_box::seal(
+            &PublicKey::from(&StaticSecret::from([2u8; 32])),
+            &header,
+            TEST_MESSAGE,
+        );
+        tx.send(
+            SealedMessage::RedBox {
+                userid: TEST_USERNAME,
+                sent_at,
+                message: &sealed,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+
+        let nxt = guard.next().await.unwrap();
+        assert!(matches!(nxt.encryption_data, EncryptionData::Failed { .. }));
+    }
 }
@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::limit::RateLimit;
+use crate::store::{RecordFile, StoreError, MASTER_KEY_LENGTH};
+
+pub const PREKEY_LEN: usize = 32;
+/// Below this many one-time prekeys, the owner is asked to publish more.
+pub const LOW_PREKEYS: usize = 10;
+/// The most one-time prekeys held for one user.
+pub const MAX_PREKEYS: usize = 100;
+/// How many bundles one user may fetch per `FETCH_WINDOW`, so that nobody
+/// can drain everyone's one-time prekeys.
+pub const MAX_FETCHES: usize = 20;
+pub const FETCH_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum PreKeyError {
+    /// The user has not published a signed prekey.
+    NotFound,
+    /// A one-time prekey has been handed out before, or is already held,
+    /// or the signed prekey has been replaced before.
+    Reused,
+    /// Holding the new one-time prekeys would go over `MAX_PREKEYS`.
+    Full,
+    /// The requester has fetched `MAX_FETCHES` bundles within the window.
+    TooManyFetches,
+    /// A prekey is the wrong length.
+    Malformed,
+    Store(StoreError),
+}
+
+impl fmt::Display for PreKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no prekeys published for this id"),
+            Self::Reused => write!(f, "one-time prekey was already used"),
+            Self::Full => write!(f, "at most {} one-time prekeys are held", MAX_PREKEYS),
+            Self::TooManyFetches => write!(f, "too many prekey fetches, try again later"),
+            Self::Malformed => write!(f, "malformed prekeys"),
+            Self::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PreKeyError {}
+
+impl From<StoreError> for PreKeyError {
+    fn from(err: StoreError) -> Self {
+        Self::Store(err)
+    }
+}
+
+/// What a sender gets to start a session with an offline user.
+pub struct Bundle {
+    pub signed_prekey: [u8; PREKEY_LEN],
+    pub prekey_signature: Vec<u8>,
+    /// `None` once the pool has run dry.
+    pub one_time_prekey: Option<[u8; PREKEY_LEN]>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PreKeyPool {
+    signed_prekey: [u8; PREKEY_LEN],
+    prekey_signature: Vec<u8>,
+    one_time: VecDeque<[u8; PREKEY_LEN]>,
+    /// The one-time prekeys handed out under the current signed prekey, so
+    /// that replaying an old upload can't put one back in the pool.
+    used: HashSet<[u8; PREKEY_LEN]>,
+    /// Signed prekeys replaced so far. An upload is signed along with its
+    /// signed prekey, so refusing these refuses every upload from before
+    /// the last one, and `used` can start over with each new signed prekey.
+    retired: HashSet<[u8; PREKEY_LEN]>,
+}
+
+/// Each user's signed prekey and pool of one-time prekeys. Opened on a file,
+/// every change is written out before it takes effect, so a one-time prekey
+/// is never handed out twice, even across restarts.
+pub struct PreKeyStore {
+    file: Option<RecordFile>,
+    pools: HashMap<String, PreKeyPool>,
+    /// Bundles fetched per requester.
+    fetches: RateLimit,
+}
+
+impl Default for PreKeyStore {
+    fn default() -> Self {
+        Self {
+            file: None,
+            pools: HashMap::new(),
+            fetches: RateLimit::new(MAX_FETCHES, FETCH_WINDOW),
+        }
+    }
+}
+
+impl PreKeyStore {
+    pub fn open(
+        path: impl AsRef<Path>,
+        master_key: &[u8; MASTER_KEY_LENGTH],
+    ) -> Result<Self, StoreError> {
+        let file = RecordFile::new(path, master_key);
+        let pools = file.load()?;
+        Ok(Self {
+            file: Some(file),
+            pools,
+            ..Self::default()
+        })
+    }
+
+    /// Replaces `userid`'s signed prekey and adds to its one-time prekeys,
+    /// given as concatenated keys. Returns how many one-time prekeys are
+    /// now held.
+    ///
+    /// A signed prekey that has been replaced can't come back.
+    pub async fn publish(
+        &mut self,
+        userid: &str,
+        signed_prekey: [u8; PREKEY_LEN],
+        prekey_signature: Vec<u8>,
+        one_time_prekeys: &[u8],
+    ) -> Result<usize, PreKeyError> {
+        let chunks = one_time_prekeys.chunks_exact(PREKEY_LEN);
+        if !chunks.remainder().is_empty() {
+            return Err(PreKeyError::Malformed);
+        }
+
+        let mut pool = match self.pools.get(userid) {
+            Some(pool) => pool.clone(),
+            None => PreKeyPool {
+                signed_prekey,
+                prekey_signature: Vec::new(),
+                one_time: VecDeque::new(),
+                used: HashSet::new(),
+                retired: HashSet::new(),
+            },
+        };
+        if pool.retired.contains(&signed_prekey) {
+            return Err(PreKeyError::Reused);
+        }
+        for prekey in chunks {
+            let prekey: [u8; PREKEY_LEN] = prekey.try_into().unwrap();
+            if pool.used.contains(&prekey) || pool.one_time.contains(&prekey) {
+                return Err(PreKeyError::Reused);
+            }
+            pool.one_time.push_back(prekey);
+        }
+        if pool.one_time.len() > MAX_PREKEYS {
+            return Err(PreKeyError::Full);
+        }
+        if pool.signed_prekey != signed_prekey {
+            pool.retired.insert(pool.signed_prekey);
+            pool.used.clear();
+        }
+        pool.signed_prekey = signed_prekey;
+        pool.prekey_signature = prekey_signature;
+
+        let remaining = pool.one_time.len();
+        self.commit(userid, pool).await?;
+        Ok(remaining)
+    }
+
+    /// Hands out `userid`'s signed prekey and, while any are left, one of
+    /// its one-time prekeys, which is then gone for good.
+    pub async fn take_bundle(&mut self, userid: &str) -> Result<Bundle, PreKeyError> {
+        let mut pool = self
+            .pools
+            .get(userid)
+            .cloned()
+            .ok_or(PreKeyError::NotFound)?;
+        let one_time_prekey = pool.one_time.pop_front();
+        if let Some(prekey) = one_time_prekey {
+            pool.used.insert(prekey);
+        }
+
+        let bundle = Bundle {
+            signed_prekey: pool.signed_prekey,
+            prekey_signature: pool.prekey_signature.clone(),
+            one_time_prekey,
+        };
+        self.commit(userid, pool).await?;
+        Ok(bundle)
+    }
+
+    /// Counts a fetch by `requester`, unless it has made `MAX_FETCHES`
+    /// within the last `FETCH_WINDOW` already.
+    pub fn allow_fetch(&mut self, requester: &str, now: Instant) -> Result<(), PreKeyError> {
+        if !self.fetches.allow(requester, now) {
+            return Err(PreKeyError::TooManyFetches);
+        }
+        Ok(())
+    }
+
+    /// How many one-time prekeys are left for `userid`, if it has published
+    /// any prekeys at all.
+    pub fn remaining(&self, userid: &str) -> Option<usize> {
+        self.pools.get(userid).map(|pool| pool.one_time.len())
+    }
+
+    pub fn is_low(&self, userid: &str) -> bool {
+        self.remaining(userid)
+            .is_some_and(|remaining| remaining < LOW_PREKEYS)
+    }
+
+    async fn commit(&mut self, userid: &str, pool: PreKeyPool) -> Result<(), PreKeyError> {
+        let old = self.pools.insert(userid.to_owned(), pool);
+        if let Some(file) = &self.file {
+            if let Err(err) = file.save(&self.pools).await {
+                // Keep memory in line with what is on disk.
+                match old {
+                    Some(old) => self.pools.insert(userid.to_owned(), old),
+                    None => self.pools.remove(userid),
+                };
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::{PreKeyError, PreKeyStore, FETCH_WINDOW, MAX_FETCHES};
+    use crate::testing::TempPath;
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const MASTER_KEY: &[u8; 32] = &[5u8; 32];
+
+    #[tokio::test]
+    async fn prekeys_are_never_reissued() {
+        let path = TempPath::new("prekeys");
+        let upload = [[1u8; 32], [2u8; 32]].concat();
+
+        let mut store = PreKeyStore::open(&path, MASTER_KEY).unwrap();
+        assert!(matches!(
+            store.take_bundle(TEST_USERNAME).await,
+            Err(PreKeyError::NotFound)
+        ));
+        assert_eq!(
+            store
+                .publish(TEST_USERNAME, [9u8; 32], vec![0u8; 64], &upload)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(store.is_low(TEST_USERNAME));
+        let bundle = store.take_bundle(TEST_USERNAME).await.unwrap();
+        assert_eq!(bundle.signed_prekey, [9u8; 32]);
+        assert_eq!(bundle.one_time_prekey, Some([1u8; 32]));
+        drop(store);
+
+        // Replaying the upload after a restart doesn't bring the used key
+        // back.
+        let mut store = PreKeyStore::open(&path, MASTER_KEY).unwrap();
+        assert!(matches!(
+            store
+                .publish(TEST_USERNAME, [9u8; 32], vec![0u8; 64], &upload)
+                .await,
+            Err(PreKeyError::Reused)
+        ));
+        let bundle = store.take_bundle(TEST_USERNAME).await.unwrap();
+        assert_eq!(bundle.one_time_prekey, Some([2u8; 32]));
+        let bundle = store.take_bundle(TEST_USERNAME).await.unwrap();
+        assert_eq!(bundle.one_time_prekey, None);
+
+        assert!(matches!(
+            store
+                .publish(TEST_USERNAME, [9u8; 32], Vec::new(), &[0u8; 33])
+                .await,
+            Err(PreKeyError::Malformed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_new_signed_prekey_retires_the_old_uploads() {
+        let mut store = PreKeyStore::default();
+        let upload = [1u8; 32];
+        store
+            .publish(TEST_USERNAME, [8u8; 32], Vec::new(), &upload)
+            .await
+            .unwrap();
+        store.take_bundle(TEST_USERNAME).await.unwrap();
+
+        // The used key is forgotten along with the signed prekey it went out
+        // under, and uploads carrying that one are refused from then on.
+        store
+            .publish(TEST_USERNAME, [9u8; 32], Vec::new(), &[2u8; 32])
+            .await
+            .unwrap();
+        assert!(store.pools[TEST_USERNAME].used.is_empty());
+        assert!(matches!(
+            store
+                .publish(TEST_USERNAME, [8u8; 32], Vec::new(), &upload)
+                .await,
+            Err(PreKeyError::Reused)
+        ));
+    }
+
+    #[test]
+    fn fetches_are_limited_per_requester() {
+        let mut store = PreKeyStore::default();
+        let now = Instant::now();
+        for _ in 0..MAX_FETCHES {
+            store.allow_fetch(TEST_USERNAME, now).unwrap();
+        }
+        assert!(matches!(
+            store.allow_fetch(TEST_USERNAME, now),
+            Err(PreKeyError::TooManyFetches)
+        ));
+        store.allow_fetch("SOMEONE_ELSE", now).unwrap();
+        store
+            .allow_fetch(TEST_USERNAME, now + FETCH_WINDOW)
+            .unwrap();
+    }
+}
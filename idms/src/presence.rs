@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::security::OwnedSealedMessage;
+
+/// Whether a user can be reached right now. On the wire this is its byte
+/// value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Presence {
+    Offline = 0,
+    /// Signed in, but nothing heard for a while.
+    Idle = 1,
+    Online = 2,
+}
+
+impl Presence {
+    #[cfg(test)]
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Offline),
+            1 => Some(Self::Idle),
+            2 => Some(Self::Online),
+            _ => None,
+        }
+    }
+}
+
+/// How long a connection may go without sending anything, heartbeats
+/// included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepalivePolicy {
+    /// After this the user shows as idle.
+    pub idle_after: Duration,
+    /// After this the connection is closed.
+    pub timeout: Duration,
+}
+
+impl Default for KeepalivePolicy {
+    fn default() -> Self {
+        Self {
+            idle_after: Duration::from_secs(60),
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    online: usize,
+    idle: usize,
+}
+
+impl Connections {
+    fn presence(&self) -> Presence {
+        if self.online > 0 {
+            Presence::Online
+        } else if self.idle > 0 {
+            Presence::Idle
+        } else {
+            Presence::Offline
+        }
+    }
+
+    fn count(&mut self, presence: Presence) -> Option<&mut usize> {
+        match presence {
+            Presence::Offline => None,
+            Presence::Idle => Some(&mut self.idle),
+            Presence::Online => Some(&mut self.online),
+        }
+    }
+}
+
+struct Watcher {
+    userid: String,
+    tx: mpsc::UnboundedSender<OwnedSealedMessage>,
+}
+
+/// Who is signed in where, across every connection. A user signed in on
+/// several connections shows as the most present of them.
+///
+/// Watchers get a `Presence` message whenever a user they watch changes.
+#[derive(Default)]
+pub struct PresenceRegistry {
+    users: HashMap<String, Connections>,
+    watchers: HashMap<String, Vec<Watcher>>,
+}
+
+impl PresenceRegistry {
+    pub fn presence(&self, userid: &str) -> Presence {
+        self.users
+            .get(userid)
+            .map_or(Presence::Offline, Connections::presence)
+    }
+
+    /// Moves one of `userid`'s connections from `from` to `to`, e.g. from
+    /// `Offline` to `Online` as it signs in.
+    pub fn update(&mut self, userid: &str, from: Presence, to: Presence) {
+        if from == to {
+            return;
+        }
+        let connections = self.users.entry(userid.to_owned()).or_default();
+        let before = connections.presence();
+        if let Some(count) = connections.count(from) {
+            *count = count.saturating_sub(1);
+        }
+        if let Some(count) = connections.count(to) {
+            *count += 1;
+        }
+        let after = connections.presence();
+        if after == Presence::Offline {
+            self.users.remove(userid);
+        }
+        if before != after {
+            self.notify(userid, after);
+        }
+    }
+
+    /// Sends `watcher` every change to `subject` until `tx`'s receiver is
+    /// dropped. Returns where `subject` stands now.
+    pub fn watch(
+        &mut self,
+        subject: &str,
+        watcher: &str,
+        tx: mpsc::UnboundedSender<OwnedSealedMessage>,
+    ) -> Presence {
+        let watchers = self.watchers.entry(subject.to_owned()).or_default();
+        watchers.retain(|watcher| !watcher.tx.is_closed());
+        watchers.push(Watcher {
+            userid: watcher.to_owned(),
+            tx,
+        });
+        self.presence(subject)
+    }
+
+    fn notify(&mut self, subject: &str, presence: Presence) {
+        if let Some(watchers) = self.watchers.get_mut(subject) {
+            watchers.retain(|watcher| {
+                let message = OwnedSealedMessage::Presence {
+                    userid: watcher.userid.clone(),
+                    subject: subject.to_owned(),
+                    status: presence as u8,
+                };
+                watcher.tx.send(message).is_ok()
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::{Presence, PresenceRegistry};
+    use crate::security::OwnedSealedMessage;
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+
+    #[test]
+    fn presence_follows_the_most_present_connection() {
+        let mut registry = PresenceRegistry::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert_eq!(
+            registry.watch(TEST_USERNAME, "WATCHER", tx),
+            Presence::Offline
+        );
+
+        registry.update(TEST_USERNAME, Presence::Offline, Presence::Online);
+        registry.update(TEST_USERNAME, Presence::Offline, Presence::Online);
+        // One of two connections going idle changes nothing.
+        registry.update(TEST_USERNAME, Presence::Online, Presence::Idle);
+        assert_eq!(registry.presence(TEST_USERNAME), Presence::Online);
+        registry.update(TEST_USERNAME, Presence::Online, Presence::Offline);
+        registry.update(TEST_USERNAME, Presence::Idle, Presence::Offline);
+
+        let mut seen = Vec::new();
+        while let Ok(OwnedSealedMessage::Presence { status, .. }) = rx.try_recv() {
+            seen.push(Presence::from_byte(status).unwrap());
+        }
+        assert_eq!(seen, [Presence::Online, Presence::Idle, Presence::Offline]);
+    }
+}
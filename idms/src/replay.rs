@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use idms::secure::sym::{DecryptError, ReplayWindow};
+
+use crate::store::{RecordFile, StoreError, MASTER_KEY_LENGTH};
+
+/// How far a `RedBox`'s `sent_at` may be from the guard's clock, either way.
+pub const RED_BOX_MAX_SKEW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Seen(DecryptError),
+    /// The window couldn't be written out, so the counter wasn't taken.
+    Store(StoreError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Seen(err) => write!(f, "{}", err),
+            Self::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<DecryptError> for ReplayError {
+    fn from(err: DecryptError) -> Self {
+        Self::Seen(err)
+    }
+}
+
+/// What every guard in the process has let through, so that a signed or
+/// sealed message can't be passed twice, on one connection or across
+/// several.
+///
+/// Opened on a file, the counter windows are written out before a message
+/// passes, so a recipient can't resubmit a signed message as its own
+/// `Forward` after a restart either. Red boxes are only remembered in
+/// memory, and ones sent before the store was opened are turned away.
+#[derive(Default)]
+pub struct SeenMessages {
+    file: Option<RecordFile>,
+    /// Per sender, per keychain version. A `Sync` starts a fresh count.
+    counters: HashMap<String, BTreeMap<u32, ReplayWindow>>,
+    /// The one-time keys of opened red boxes, with when they were sent.
+    red_boxes: HashMap<[u8; 32], u64>,
+    /// Unix seconds. Boxes sent at or before this may have been opened by
+    /// an earlier run.
+    red_boxes_after: u64,
+}
+
+impl SeenMessages {
+    pub fn open(
+        path: impl AsRef<Path>,
+        master_key: &[u8; MASTER_KEY_LENGTH],
+    ) -> Result<Self, StoreError> {
+        let file = RecordFile::new(path, master_key);
+        let counters = file.load()?;
+        Ok(Self {
+            file: Some(file),
+            counters,
+            red_boxes_after: unix_seconds(SystemTime::now()),
+            ..Self::default()
+        })
+    }
+
+    /// Lets `counter` through once for `userid`'s keychain `version`. Only
+    /// call this once the signature over it has verified, or forgeries
+    /// could push the window forward.
+    pub async fn counter(
+        &mut self,
+        userid: &str,
+        version: u32,
+        counter: u64,
+    ) -> Result<(), ReplayError> {
+        let versions = self.counters.entry(userid.to_owned()).or_default();
+        let old = versions.clone();
+        // Only the current keychain and the one it replaced still verify.
+        versions.retain(|&seen, _| seen.saturating_add(1) >= version);
+        let window = versions.entry(version).or_default();
+        window.check(counter)?;
+        window.accept(counter);
+
+        if let Some(file) = &self.file {
+            if let Err(err) = file.save(&self.counters).await {
+                // Keep memory in line with what is on disk.
+                self.counters.insert(userid.to_owned(), old);
+                return Err(ReplayError::Store(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets the red box with one-time key `ephemeral` through once, provided
+    /// it was sent, in Unix seconds, within `RED_BOX_MAX_SKEW` of `now`.
+    pub fn red_box(
+        &mut self,
+        ephemeral: [u8; 32],
+        sent_at: u64,
+        now: SystemTime,
+    ) -> Result<(), DecryptError> {
+        let now = unix_seconds(now);
+        let skew = RED_BOX_MAX_SKEW.as_secs();
+        let oldest = now.saturating_sub(skew);
+        if sent_at < oldest || sent_at <= self.red_boxes_after || sent_at > now.saturating_add(skew)
+        {
+            return Err(DecryptError::TooOld);
+        }
+        // Boxes this old are turned away anyway, so no need to remember them.
+        self.red_boxes.retain(|_, sent_at| *sent_at >= oldest);
+        if self.red_boxes.insert(ephemeral, sent_at).is_some() {
+            return Err(DecryptError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use idms::secure::sym::DecryptError;
+
+    use super::{ReplayError, SeenMessages, RED_BOX_MAX_SKEW};
+    use crate::testing::TempPath;
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const MASTER_KEY: &[u8; 32] = &[5u8; 32];
+
+    #[tokio::test]
+    async fn counters_and_boxes_pass_once() {
+        let mut seen = SeenMessages::default();
+        seen.counter(TEST_USERNAME, 1, 5).await.unwrap();
+        assert!(matches!(
+            seen.counter(TEST_USERNAME, 1, 5).await,
+            Err(ReplayError::Seen(DecryptError::Replayed))
+        ));
+        // Out of order is fine, and so is a new keychain counting afresh.
+        seen.counter(TEST_USERNAME, 1, 4).await.unwrap();
+        seen.counter(TEST_USERNAME, 2, 0).await.unwrap();
+        seen.counter("SOMEONE_ELSE", 1, 5).await.unwrap();
+
+        let now = SystemTime::now();
+        let sent_at = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        seen.red_box([1u8; 32], sent_at, now).unwrap();
+        assert_eq!(
+            seen.red_box([1u8; 32], sent_at, now),
+            Err(DecryptError::Replayed)
+        );
+        let stale = sent_at - RED_BOX_MAX_SKEW.as_secs() - 1;
+        assert_eq!(
+            seen.red_box([2u8; 32], stale, now),
+            Err(DecryptError::TooOld)
+        );
+        assert_eq!(
+            seen.red_box([3u8; 32], u64::MAX, now),
+            Err(DecryptError::TooOld)
+        );
+    }
+
+    #[tokio::test]
+    async fn counters_survive_reopen() {
+        let path = TempPath::new("seen");
+        let mut seen = SeenMessages::open(&path, MASTER_KEY).unwrap();
+        seen.counter(TEST_USERNAME, 1, 5).await.unwrap();
+        drop(seen);
+
+        let mut seen = SeenMessages::open(&path, MASTER_KEY).unwrap();
+        assert!(matches!(
+            seen.counter(TEST_USERNAME, 1, 5).await,
+            Err(ReplayError::Seen(DecryptError::Replayed))
+        ));
+        seen.counter(TEST_USERNAME, 1, 6).await.unwrap();
+
+        // What was opened before the restart isn't known, so boxes from
+        // then are refused.
+        let now = SystemTime::now();
+        let sent_at = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(
+            seen.red_box([1u8; 32], sent_at - 1, now),
+            Err(DecryptError::TooOld)
+        );
+    }
+}
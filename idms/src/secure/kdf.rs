@@ -0,0 +1,11 @@
+use ring::hkdf::KeyType;
+
+/// An HKDF output length, for expanding into plain byte buffers rather
+/// than `ring` key types.
+pub(crate) struct Len(pub(crate) usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
@@ -1,61 +1,178 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
-use x25519_dalek::{PublicKey, EphemeralSecret, SharedSecret, StaticSecret};
-use ring::aead::{SealingKey, UnboundKey, CHACHA20_POLY1305, BoundKey, Nonce, NONCE_LEN, NonceSequence, Algorithm};
-use ring::rand::{generate, SystemRandom, SecureRandom};
+use x25519_dalek::{PublicKey, EphemeralSecret, StaticSecret};
+use ring::aead::{CHACHA20_POLY1305, Algorithm};
+use ring::digest::{self, SHA256};
+use ring::hkdf::{Prk, Salt, HKDF_SHA256};
+use ring::hmac;
 
+use super::kdf::Len;
 use super::sym::SymContext;
 
 const SHARED_SECRET_LENGTH: usize = 32;
-static SYMM_ALG: &'static Algorithm = &CHACHA20_POLY1305;
+static SYMM_ALG: &Algorithm = &CHACHA20_POLY1305;
+
+const SALT_LABEL: &[u8] = b"idms shared key";
+const ENC_INFO: &[u8] = b"idms enc";
+const MAC_INFO: &[u8] = b"idms mac";
+const EXPORTER_INFO: &[u8] = b"idms exporter";
+/// The most `export` gives for one label: HKDF-SHA256's limit.
+pub const MAX_EXPORT_LEN: usize = 255 * 32;
+
+/// The X25519 points of small order, with the top bit clear. DH with any of
+/// them gives an output the sender knows without a secret, zero included.
+const LOW_ORDER_POINTS: [[u8; 32]; 7] = [
+    [0; 32],
+    [
+        1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0,
+    ],
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    // p - 1, then p and p + 1, which are 0 and 1 again.
+    low_order_near_p(0xec),
+    low_order_near_p(0xed),
+    low_order_near_p(0xee),
+];
+
+const fn low_order_near_p(low: u8) -> [u8; 32] {
+    let mut point = [0xff; 32];
+    point[0] = low;
+    point[31] = 0x7f;
+    point
+}
 
+/// Whether DH with `key` would give an output its sender knows without a
+/// secret. X25519 ignores the top bit, so it can't make a point safe.
+pub fn is_low_order(key: &PublicKey) -> bool {
+    let mut point = *key.as_bytes();
+    point[31] &= 0x7f;
+    LOW_ORDER_POINTS.contains(&point)
+}
 
+
+/// The keys two parties share after an X25519 exchange. The DH output is
+/// run through HKDF, salted with both public keys, and each use gets its own
+/// labeled output: one encryption key per sender, a MAC key, and an
+/// exporter for anything else built on top.
 pub struct SharedKey {
-    secret: SharedSecret,
-    _ctx: SymContext,
+    secret: [u8; SHARED_SECRET_LENGTH],
+    exporter: Prk,
+    mac: hmac::Key,
+    sender: SymContext,
+    receiver: SymContext,
 }
 
 impl SharedKey {
-    fn create_context(secret: &SharedSecret) -> SymContext {
-        SymContext::new(
-            secret.to_bytes(),
-            SYMM_ALG,
-        )
-    }
+    /// Runs the key schedule over `secret`, e.g. an X3DH output, agreed
+    /// between the holders of `local` and `remote`.
+    pub(crate) fn schedule(secret: [u8; SHARED_SECRET_LENGTH], local: PublicKey, remote: PublicKey) -> Self {
+        // Order the keys so that both ends compute the same salt.
+        let (low, high) = if local.as_bytes() < remote.as_bytes() {
+            (local, remote)
+        } else {
+            (remote, local)
+        };
+        let mut transcript = digest::Context::new(&SHA256);
+        transcript.update(SALT_LABEL);
+        transcript.update(low.as_bytes());
+        transcript.update(high.as_bytes());
+        let salt = transcript.finish();
+
+        let prk = Salt::new(HKDF_SHA256, salt.as_ref()).extract(&secret);
+        let expand = |info: &[&[u8]]| {
+            let mut okm = [0u8; SHARED_SECRET_LENGTH];
+            prk.expand(info, Len(okm.len()))
+                .and_then(|expanded| expanded.fill(&mut okm))
+                .unwrap();
+            okm
+        };
 
-    pub fn derive_eph(public: PublicKey, private: EphemeralSecret) -> Self {
-        let secret = private.diffie_hellman(&public);
-        let ctx = SymContext::new(secret.to_bytes(), SYMM_ALG);
         Self {
+            exporter: Prk::new_less_safe(HKDF_SHA256, &expand(&[EXPORTER_INFO])),
+            mac: hmac::Key::new(hmac::HMAC_SHA256, &expand(&[MAC_INFO])),
+            sender: SymContext::new(expand(&[ENC_INFO, local.as_bytes()]), SYMM_ALG),
+            receiver: SymContext::new(expand(&[ENC_INFO, remote.as_bytes()]), SYMM_ALG),
             secret,
-            _ctx: ctx,
         }
     }
 
+    pub fn derive_eph(public: PublicKey, private: EphemeralSecret) -> Self {
+        let local = PublicKey::from(&private);
+        let secret = private.diffie_hellman(&public).to_bytes();
+        Self::schedule(secret, local, public)
+    }
+
     pub fn derive_stat(public: PublicKey, private: StaticSecret) -> Self {
-        let secret = private.diffie_hellman(&public);
-        let ctx = SymContext::new(secret.to_bytes(), SYMM_ALG);
-        Self {
-            secret,
-            _ctx: ctx,
-        }
+        let local = PublicKey::from(&private);
+        let secret = private.diffie_hellman(&public).to_bytes();
+        Self::schedule(secret, local, public)
     }
 
-    pub fn bytes(&self) -> [u8; SHARED_SECRET_LENGTH] {
-        self.secret.to_bytes()
+    /// The raw shared secret, for protocols like Noise that run their own
+    /// key schedule over it. Anything else should use the derived keys.
+    pub(crate) fn bytes(&self) -> [u8; SHARED_SECRET_LENGTH] {
+        self.secret
     }
 
-    pub fn aes_key(&self) -> UnboundKey {
-        UnboundKey::new(&CHACHA20_POLY1305, &self.bytes()).unwrap()
+    /// Seals messages from this end. Clones share a counter.
+    pub fn sender(&self) -> SymContext {
+        self.sender.clone()
     }
 
-    pub fn ctx(&self) -> SymContext {
-        self._ctx.clone()
+    /// Opens messages from the other end.
+    pub fn receiver(&self) -> SymContext {
+        self.receiver.clone()
     }
 
-}
+    /// The HMAC key for `Seal`s between the two ends.
+    pub fn mac_key(&self) -> hmac::Key {
+        self.mac.clone()
+    }
 
+    /// Fills `out` with secret bytes bound to `label`, which both ends get
+    /// alike. Different labels give independent outputs.
+    ///
+    /// # Panics
+    ///
+    /// If `out` is longer than `MAX_EXPORT_LEN`.
+    pub fn export(&self, label: &[u8], out: &mut [u8]) {
+        assert!(out.len() <= MAX_EXPORT_LEN, "export is limited to {} bytes", MAX_EXPORT_LEN);
+        self.exporter
+            .expand(&[label], Len(out.len()))
+            .and_then(|expanded| expanded.fill(out))
+            .unwrap();
+    }
 
+}
 
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{SharedKey, MAX_EXPORT_LEN};
+
+    #[test]
+    fn exports_are_not_the_raw_secret() {
+        let alice = StaticSecret::new(OsRng);
+        let key = SharedKey::derive_stat(PublicKey::from(&StaticSecret::new(OsRng)), alice);
+        let mut out = [0u8; 32];
+        key.export(b"test", &mut out);
+        assert_ne!(out, key.bytes());
+        key.export(b"test", &mut vec![0u8; MAX_EXPORT_LEN]);
+    }
 
+    #[test]
+    #[should_panic(expected = "export is limited")]
+    fn exports_stop_at_the_hkdf_limit() {
+        let key = SharedKey::derive_stat(PublicKey::from(&StaticSecret::new(OsRng)), StaticSecret::new(OsRng));
+        key.export(b"test", &mut vec![0u8; MAX_EXPORT_LEN + 1]);
+    }
+}
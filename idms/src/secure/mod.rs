@@ -1,4 +1,8 @@
+mod kdf;
 pub mod keys;
+pub mod ratchet;
+pub mod red_box;
 pub mod seal;
 pub mod secure_channel;
 pub mod sym;
+pub mod x3dh;
@@ -0,0 +1,342 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use rand_core::OsRng;
+use ring::aead::{Algorithm, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::keys::SharedKey;
+use super::kdf::Len;
+use super::sym::SymContext;
+
+/// How many message keys one message may skip over in a chain.
+pub const MAX_SKIP: u32 = 1000;
+/// How many skipped message keys a session holds on to, oldest dropped first.
+pub const MAX_SKIPPED: usize = 2000;
+pub const HEADER_LEN: usize = 40;
+
+const ROOT_LABEL: &[u8] = b"idms ratchet root";
+const ROOT_INFO: &[u8] = b"idms ratchet";
+static RATCHET_ALG: &Algorithm = &CHACHA20_POLY1305;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatchetError {
+    /// The header is the wrong length.
+    Malformed,
+    /// Opening the message would skip more than `MAX_SKIP` keys.
+    TooManySkipped,
+    /// The message failed to authenticate, or its key was already used.
+    Unauthentic,
+    /// The responder has to receive a message before it can send one.
+    NotReady,
+    /// The sending chain has numbered as many messages as it can. The peer
+    /// has to reply before this end sends again.
+    Exhausted,
+}
+
+impl fmt::Display for RatchetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed ratchet header"),
+            Self::TooManySkipped => write!(f, "too many skipped messages"),
+            Self::Unauthentic => write!(f, "message failed to authenticate"),
+            Self::NotReady => write!(f, "nothing received to reply to yet"),
+            Self::Exhausted => write!(f, "sending chain is used up"),
+        }
+    }
+}
+
+impl std::error::Error for RatchetError {}
+
+/// Sent in the clear ahead of each message: the sender's current ratchet
+/// key, the length of its previous sending chain, and the message's number
+/// in the current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub public: [u8; 32],
+    pub previous: u32,
+    pub number: u32,
+}
+
+impl Header {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..32].copy_from_slice(&self.public);
+        bytes[32..36].copy_from_slice(&self.previous.to_be_bytes());
+        bytes[36..].copy_from_slice(&self.number.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, RatchetError> {
+        if bytes.len() != HEADER_LEN {
+            return Err(RatchetError::Malformed);
+        }
+        Ok(Self {
+            public: bytes[..32].try_into().unwrap(),
+            previous: u32::from_be_bytes(bytes[32..36].try_into().unwrap()),
+            number: u32::from_be_bytes(bytes[36..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Identifies a skipped message key: the sender's ratchet key and the
+/// message's number under it.
+type SkippedId = ([u8; 32], u32);
+
+/// A Double Ratchet session between two users. Every message gets its own
+/// key from a symmetric chain, and each reply moves both chains on with a
+/// fresh DH, so a stolen state neither reads old messages nor keeps reading
+/// new ones once the peer has replied.
+#[derive(Clone)]
+pub struct Session {
+    chains: Chains,
+    skipped: HashMap<SkippedId, [u8; 32]>,
+    skipped_order: VecDeque<SkippedId>,
+}
+
+/// Everything but the skipped keys. Small enough to copy, so that `decrypt`
+/// can work on a copy and keep it only once the message authenticates.
+#[derive(Clone)]
+struct Chains {
+    ratchet_key: StaticSecret,
+    remote: Option<PublicKey>,
+    root: [u8; 32],
+    sending: Option<[u8; 32]>,
+    receiving: Option<[u8; 32]>,
+    sent: u32,
+    received: u32,
+    previous: u32,
+}
+
+impl Session {
+    fn start(shared: &SharedKey, ratchet_key: StaticSecret) -> Self {
+        let mut root = [0u8; 32];
+        shared.export(ROOT_LABEL, &mut root);
+        Self {
+            chains: Chains {
+                ratchet_key,
+                remote: None,
+                root,
+                sending: None,
+                receiving: None,
+                sent: 0,
+                received: 0,
+                previous: 0,
+            },
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        }
+    }
+
+    /// Starts the side that sends first, towards the peer's ratchet key.
+    pub fn initiate(shared: &SharedKey, remote: PublicKey) -> Self {
+        let mut session = Self::start(shared, StaticSecret::new(OsRng));
+        let chains = &mut session.chains;
+        let (root, chain) = kdf_root(&chains.root, &chains.ratchet_key.diffie_hellman(&remote).to_bytes());
+        chains.root = root;
+        chains.sending = Some(chain);
+        chains.remote = Some(remote);
+        session
+    }
+
+    /// Starts the side that waits for the first message, holding the secret
+    /// half of the ratchet key the initiator was given.
+    pub fn respond(shared: &SharedKey, ratchet_key: StaticSecret) -> Self {
+        Self::start(shared, ratchet_key)
+    }
+
+    /// Seals `plaintext` as the next message. The result is the encoded
+    /// header followed by the ciphertext; `aad` is authenticated alongside.
+    pub fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        let chains = &mut self.chains;
+        let chain = chains.sending.ok_or(RatchetError::NotReady)?;
+        let sent = chains.sent.checked_add(1).ok_or(RatchetError::Exhausted)?;
+        let (chain, message_key) = kdf_chain(&chain);
+
+        let header = Header {
+            public: PublicKey::from(&chains.ratchet_key).to_bytes(),
+            previous: chains.previous,
+            number: chains.sent,
+        }
+        .encode();
+        let mut payload = plaintext.to_vec();
+        SymContext::new(message_key, RATCHET_ALG)
+            .encrypt(&associated(aad, &header), &mut payload)
+            .map_err(|_| RatchetError::Exhausted)?;
+        chains.sending = Some(chain);
+        chains.sent = sent;
+
+        let mut message = header.to_vec();
+        message.extend(payload);
+        Ok(message)
+    }
+
+    /// Opens a message from `encrypt`, in or out of order. The session only
+    /// moves on if the message authenticates.
+    pub fn decrypt(&mut self, aad: &[u8], message: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        if message.len() < HEADER_LEN {
+            return Err(RatchetError::Malformed);
+        }
+        let (header, ciphertext) = message.split_at(HEADER_LEN);
+        let aad = associated(aad, header);
+        let header = Header::decode(header)?;
+
+        // A skipped key opens the message as it is. Otherwise the chains
+        // move on in a copy, which is kept only if the message opens.
+        let id = (header.public, header.number);
+        let (message_key, staged) = match self.skipped.get(&id) {
+            Some(key) => (*key, None),
+            None => {
+                let mut chains = self.chains.clone();
+                let mut skipped = Vec::new();
+                let key = chains.message_key(&header, &mut skipped)?;
+                (key, Some((chains, skipped)))
+            }
+        };
+        let mut payload = ciphertext.to_vec();
+        let plaintext = SymContext::new(message_key, RATCHET_ALG)
+            .decrypt(&aad, &mut payload)
+            .map_err(|_| RatchetError::Unauthentic)?
+            .to_vec();
+
+        match staged {
+            Some((chains, skipped)) => {
+                self.chains = chains;
+                for (id, key) in skipped {
+                    self.skip(id, key);
+                }
+            }
+            None => {
+                self.skipped.remove(&id);
+                self.skipped_order.retain(|skipped| *skipped != id);
+            }
+        }
+        Ok(plaintext)
+    }
+
+    /// Holds on to the key of a message not yet seen, dropping the oldest
+    /// once there are more than `MAX_SKIPPED`.
+    fn skip(&mut self, id: SkippedId, key: [u8; 32]) {
+        self.skipped.insert(id, key);
+        self.skipped_order.push_back(id);
+        if self.skipped_order.len() > MAX_SKIPPED {
+            let oldest = self.skipped_order.pop_front().unwrap();
+            self.skipped.remove(&oldest);
+        }
+    }
+}
+
+impl Chains {
+    /// Moves the receiving chain on to `header`'s message and gives its key.
+    /// The keys of messages passed over on the way go to `skipped`.
+    fn message_key(&mut self, header: &Header, skipped: &mut Vec<(SkippedId, [u8; 32])>)
+        -> Result<[u8; 32], RatchetError>
+    {
+        if self.remote.map(|remote| remote.to_bytes()) != Some(header.public) {
+            self.skip_to(header.previous, skipped)?;
+            self.step(PublicKey::from(header.public));
+        }
+        self.skip_to(header.number, skipped)?;
+
+        let (chain, message_key) = kdf_chain(&self.receiving.ok_or(RatchetError::Unauthentic)?);
+        self.receiving = Some(chain);
+        // No sender numbers a message `u32::MAX`.
+        self.received = self.received.checked_add(1).ok_or(RatchetError::Unauthentic)?;
+        Ok(message_key)
+    }
+
+    /// Passes over the messages not yet seen in the receiving chain, up to
+    /// message `until`.
+    fn skip_to(&mut self, until: u32, skipped: &mut Vec<(SkippedId, [u8; 32])>)
+        -> Result<(), RatchetError>
+    {
+        let chain = match self.receiving {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        if until < self.received {
+            return Err(RatchetError::Unauthentic);
+        }
+        if until - self.received > MAX_SKIP {
+            return Err(RatchetError::TooManySkipped);
+        }
+
+        let remote = self.remote.unwrap().to_bytes();
+        let mut chain = chain;
+        while self.received < until {
+            let (next, message_key) = kdf_chain(&chain);
+            chain = next;
+            skipped.push(((remote, self.received), message_key));
+            self.received += 1;
+        }
+        self.receiving = Some(chain);
+        Ok(())
+    }
+
+    /// The DH ratchet: the peer has a new key, so derive a receiving chain
+    /// for it, then pick a new key of our own for the next sending chain.
+    fn step(&mut self, remote: PublicKey) {
+        self.previous = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.remote = Some(remote);
+
+        let (root, chain) = kdf_root(&self.root, &self.ratchet_key.diffie_hellman(&remote).to_bytes());
+        self.root = root;
+        self.receiving = Some(chain);
+
+        self.ratchet_key = StaticSecret::new(OsRng);
+        let (root, chain) = kdf_root(&self.root, &self.ratchet_key.diffie_hellman(&remote).to_bytes());
+        self.root = root;
+        self.sending = Some(chain);
+    }
+}
+
+fn associated(aad: &[u8], header: &[u8]) -> Vec<u8> {
+    let mut associated = aad.to_vec();
+    associated.extend_from_slice(header);
+    associated
+}
+
+/// Mixes a DH output into the root key, giving the new root and a chain key.
+fn kdf_root(root: &[u8; 32], dh: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Salt::new(HKDF_SHA256, root)
+        .extract(dh)
+        .expand(&[ROOT_INFO], Len(okm.len()))
+        .and_then(|expanded| expanded.fill(&mut okm))
+        .unwrap();
+    (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+}
+
+/// Steps a chain key, giving the next chain key and a message key.
+fn kdf_chain(chain: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, chain);
+    let next = hmac::sign(&key, &[2]);
+    let message = hmac::sign(&key, &[1]);
+    (next.as_ref().try_into().unwrap(), message.as_ref().try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{RatchetError, Session};
+    use crate::secure::keys::SharedKey;
+
+    #[test]
+    fn sending_stops_when_the_chain_is_used_up() {
+        let alice = StaticSecret::new(OsRng);
+        let bob = StaticSecret::new(OsRng);
+        let shared = SharedKey::derive_stat(PublicKey::from(&bob), alice);
+        let mut session = Session::initiate(&shared, PublicKey::from(&StaticSecret::new(OsRng)));
+
+        session.chains.sent = u32::MAX - 1;
+        session.encrypt(b"alice", b"last").unwrap();
+        assert_eq!(session.encrypt(b"alice", b"one more"), Err(RatchetError::Exhausted));
+        assert_eq!(session.chains.sent, u32::MAX);
+    }
+}
@@ -0,0 +1,38 @@
+use rand_core::OsRng;
+use ring::error::Unspecified;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use super::keys::SharedKey;
+
+const PUBLIC_KEY_LENGTH: usize = 32;
+
+/// Encrypts `payload` so that only the holder of `recipient`'s secret can read
+/// it, without revealing who sent it. The output is the sender's one-time
+/// public key followed by the ciphertext. `aad` is authenticated but not
+/// included, so the recipient must know it to open the box.
+pub fn seal(recipient: &PublicKey, aad: &[u8], payload: &[u8]) -> Vec<u8> {
+    let ephemeral = EphemeralSecret::new(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let mut ciphertext = payload.to_vec();
+    SharedKey::derive_eph(*recipient, ephemeral).sender().encrypt(aad, &mut ciphertext).unwrap();
+
+    let mut sealed = ephemeral_public.as_bytes().to_vec();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+pub fn open(recipient: &StaticSecret, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Unspecified> {
+    if sealed.len() < PUBLIC_KEY_LENGTH {
+        return Err(Unspecified);
+    }
+    let (ephemeral_public, ciphertext) = sealed.split_at(PUBLIC_KEY_LENGTH);
+    let ephemeral_public: [u8; PUBLIC_KEY_LENGTH] = ephemeral_public.try_into().unwrap();
+
+    let mut payload = ciphertext.to_vec();
+    let plaintext = SharedKey::derive_stat(PublicKey::from(ephemeral_public), recipient.clone())
+        .receiver()
+        .decrypt(aad, &mut payload)?
+        .to_vec();
+    Ok(plaintext)
+}
@@ -1,18 +1,82 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use bincode::Options;
+use ring::hmac;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use x25519_dalek::{PublicKey, SharedSecret};
 
-// type HMAC = [u8];
+#[derive(Debug)]
+pub enum SealError {
+    /// The tag doesn't match the data under this key.
+    Forged,
+    /// The value doesn't encode, or the data verified but doesn't decode as
+    /// a `T`.
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for SealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forged => write!(f, "seal does not verify"),
+            Self::Malformed(err) => write!(f, "sealed data is malformed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// A type that can be sealed. Its `DOMAIN` goes into every tag, so that a
+/// seal made for one type never verifies as another, even where the two
+/// encode alike. No two types may share one.
+pub trait Sealable: Serialize + DeserializeOwned {
+    const DOMAIN: &'static [u8];
+}
+
+/// A value paired with an HMAC over its encoding. The contents are readable
+/// by anyone, but can't be changed without the key.
+///
+/// The value is kept encoded, and only decoded by `open` once the tag checks
+/// out. Both sides encode with bincode's default options, which give one
+/// encoding per value as long as `T` has no unordered collections; use a
+/// `BTreeMap` over a `HashMap`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Seal<T> {
+    data: Vec<u8>,
+    tag: Vec<u8>,
+    #[serde(skip)]
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: Sealable> Seal<T> {
+    pub fn new(value: &T, key: &hmac::Key) -> Result<Self, SealError> {
+        let data = bincode::options().serialize(value).map_err(SealError::Malformed)?;
+        let tag = hmac::sign(key, &Self::signed(&data)).as_ref().to_vec();
+        Ok(Self {
+            data,
+            tag,
+            _type: PhantomData,
+        })
+    }
 
-// #[derive(Debug, Deserialize, Serialize)]
-// struct Seal<T> 
-//     where T: for<'a> Deserialize<'a> + Serialize {
-//         data: T,
-//         seal: HMAC,
-// }
+    /// Checks the tag in constant time.
+    pub fn verify(&self, key: &hmac::Key) -> bool {
+        hmac::verify(key, &Self::signed(&self.data), &self.tag).is_ok()
+    }
 
-// impl Seal {
-//     fn verify(&self, key: SharedSecret) -> bool {
-        
-//     }
-// }
+    /// What the tag covers: the length-prefixed domain, then the data.
+    fn signed(data: &[u8]) -> Vec<u8> {
+        let mut signed = (T::DOMAIN.len() as u32).to_be_bytes().to_vec();
+        signed.extend(T::DOMAIN);
+        signed.extend(data);
+        signed
+    }
 
+    pub fn open(&self, key: &hmac::Key) -> Result<T, SealError> {
+        if !self.verify(key) {
+            return Err(SealError::Forged);
+        }
+        bincode::options().deserialize(&self.data).map_err(SealError::Malformed)
+    }
+}
@@ -1 +1,340 @@
-use x25519_dalek::{};
\ No newline at end of file
+use std::fmt;
+
+use rand_core::OsRng;
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::digest::{self, SHA256, SHA256_OUTPUT_LEN};
+use ring::hkdf::{self, HKDF_SHA256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::keys::{self, SharedKey};
+use super::kdf::Len;
+use super::sym::SymContext;
+
+/// The message pattern is Noise XX, but this is not wire-compatible with
+/// `Noise_XX_25519_ChaChaPoly_SHA256`: `h` starts as the hash of the name
+/// rather than the name, `split` mixes in `h`, and transport nonces are
+/// laid out by `SymContext`. The name says so, so that nobody expects interop.
+const PROTOCOL_NAME: &[u8] = b"idms_XX_25519_ChaChaPoly_SHA256";
+const DH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+static CHANNEL_ALG: &Algorithm = &CHACHA20_POLY1305;
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    Malformed,
+    Decrypt,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "handshake i/o failed: {}", err),
+            Self::Malformed => write!(f, "malformed handshake message"),
+            Self::Decrypt => write!(f, "handshake payload failed to authenticate"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// The result of a completed handshake: one cipher per direction plus the
+/// peer's authenticated static key. Callers decide whether that key is
+/// allowed to talk to them.
+pub struct Transport {
+    pub sender: SymContext,
+    pub receiver: SymContext,
+    remote_static: PublicKey,
+}
+
+impl Transport {
+    pub fn remote_static(&self) -> &PublicKey {
+        &self.remote_static
+    }
+
+    pub fn split(self) -> (SymContext, SymContext) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// Chaining key and transcript hash, as in Noise's `SymmetricState`, with
+/// its `CipherState` folded in.
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<[u8; 32]>,
+    /// The nonce for the next encryption or decryption under `k`. Back to 0
+    /// whenever `k` changes, so no nonce is used twice under one key.
+    n: u64,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let mut h = [0u8; SHA256_OUTPUT_LEN];
+        h.copy_from_slice(digest::digest(&SHA256, PROTOCOL_NAME).as_ref());
+        Self { ck: h, h, k: None, n: 0 }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut ctx = digest::Context::new(&SHA256);
+        ctx.update(&self.h);
+        ctx.update(data);
+        self.h.copy_from_slice(ctx.finish().as_ref());
+    }
+
+    fn mix_key(&mut self, shared: SharedKey) {
+        let [ck, k] = Self::hkdf(&self.ck, &shared.bytes());
+        self.ck = ck;
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    /// The key and nonce for the next encryption or decryption, if a key has
+    /// been mixed in yet. Nonces are laid out as in Noise: four zero bytes,
+    /// then `n` little-endian.
+    fn next_cipher(&mut self) -> Option<(LessSafeKey, Nonce)> {
+        let k = self.k?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.n.to_le_bytes());
+        // A handshake uses a handful of nonces per key.
+        self.n += 1;
+        let key = LessSafeKey::new(UnboundKey::new(CHANNEL_ALG, &k).unwrap());
+        Some((key, Nonce::assume_unique_for_key(nonce)))
+    }
+
+    /// Encrypts with the transcript hash as associated data, so that the
+    /// ciphertext only opens at this point of this handshake.
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut payload = plaintext.to_vec();
+        if let Some((key, nonce)) = self.next_cipher() {
+            key.seal_in_place_append_tag(nonce, Aad::from(&self.h), &mut payload).unwrap();
+        }
+        self.mix_hash(&payload);
+        payload
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let mut payload = ciphertext.to_vec();
+        let plaintext = match self.next_cipher() {
+            Some((key, nonce)) => key
+                .open_in_place(nonce, Aad::from(&self.h), &mut payload)
+                .map_err(|_| HandshakeError::Decrypt)?
+                .to_vec(),
+            None => payload,
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Derives the two transport keys. The transcript hash is mixed in here
+    /// so that the keys are bound to every handshake message.
+    fn split(&self) -> (SymContext, SymContext) {
+        let [k1, k2] = Self::hkdf(&self.ck, &self.h);
+        (
+            SymContext::new(k1, CHANNEL_ALG),
+            SymContext::new(k2, CHANNEL_ALG),
+        )
+    }
+
+    fn hkdf(ck: &[u8; 32], ikm: &[u8]) -> [[u8; 32]; 2] {
+        let mut okm = [0u8; 64];
+        let prk = hkdf::Salt::new(HKDF_SHA256, ck).extract(ikm);
+        prk.expand(&[], Len(okm.len()))
+            .and_then(|expanded| expanded.fill(&mut okm))
+            .unwrap();
+
+        let mut out = [[0u8; 32]; 2];
+        out[0].copy_from_slice(&okm[..32]);
+        out[1].copy_from_slice(&okm[32..]);
+        out
+    }
+}
+
+fn dh(private: &StaticSecret, public: &PublicKey) -> SharedKey {
+    SharedKey::derive_stat(*public, private.clone())
+}
+
+/// Reads a peer's key, turning away the points of small order: DH with one
+/// of them would mix in a value anyone can compute.
+fn public_key(bytes: &[u8]) -> Result<PublicKey, HandshakeError> {
+    let bytes: [u8; DH_LEN] = bytes.try_into().map_err(|_| HandshakeError::Malformed)?;
+    let key = PublicKey::from(bytes);
+    if keys::is_low_order(&key) {
+        return Err(HandshakeError::Malformed);
+    }
+    Ok(key)
+}
+
+async fn write_message<S>(stream: &mut S, message: &[u8]) -> Result<(), HandshakeError>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u16(message.len() as u16).await?;
+    stream.write_all(message).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_message<S>(stream: &mut S, expected: usize) -> Result<Vec<u8>, HandshakeError>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await? as usize;
+    if len != expected {
+        return Err(HandshakeError::Malformed);
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Runs the initiator side of an XX handshake:
+///
+/// ```text
+/// -> e
+/// <- e, ee, s, es, payload
+/// -> s, se, payload
+/// ```
+///
+/// The payloads are empty. They are sent anyway, because until something
+/// is encrypted under `es` or `se`, nothing proves the sender holds the
+/// static key it sent.
+pub async fn initiate<S>(stream: &mut S, local: &StaticSecret) -> Result<Transport, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new();
+    let e = StaticSecret::new(OsRng);
+    let e_pub = PublicKey::from(&e);
+
+    // -> e
+    state.mix_hash(e_pub.as_bytes());
+    write_message(stream, e_pub.as_bytes()).await?;
+
+    // <- e, ee, s, es, payload
+    let message = read_message(stream, DH_LEN * 2 + TAG_LEN * 2).await?;
+    let re = public_key(&message[..DH_LEN])?;
+    state.mix_hash(re.as_bytes());
+    state.mix_key(dh(&e, &re));
+    let rs = public_key(&state.decrypt_and_hash(&message[DH_LEN..DH_LEN * 2 + TAG_LEN])?)?;
+    state.mix_key(dh(&e, &rs));
+    state.decrypt_and_hash(&message[DH_LEN * 2 + TAG_LEN..])?;
+
+    // -> s, se, payload
+    let mut message = state.encrypt_and_hash(PublicKey::from(local).as_bytes());
+    state.mix_key(dh(local, &re));
+    message.extend(state.encrypt_and_hash(&[]));
+    write_message(stream, &message).await?;
+
+    let (sender, receiver) = state.split();
+    Ok(Transport {
+        sender,
+        receiver,
+        remote_static: rs,
+    })
+}
+
+/// Runs the responder side of the handshake started by [`initiate`].
+pub async fn respond<S>(stream: &mut S, local: &StaticSecret) -> Result<Transport, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new();
+    let e = StaticSecret::new(OsRng);
+    let e_pub = PublicKey::from(&e);
+
+    // -> e
+    let message = read_message(stream, DH_LEN).await?;
+    let re = public_key(&message)?;
+    state.mix_hash(re.as_bytes());
+
+    // <- e, ee, s, es, payload
+    state.mix_hash(e_pub.as_bytes());
+    state.mix_key(dh(&e, &re));
+    let mut message = e_pub.as_bytes().to_vec();
+    message.extend(state.encrypt_and_hash(PublicKey::from(local).as_bytes()));
+    state.mix_key(dh(local, &re));
+    message.extend(state.encrypt_and_hash(&[]));
+    write_message(stream, &message).await?;
+
+    // -> s, se, payload
+    let message = read_message(stream, DH_LEN + TAG_LEN * 2).await?;
+    let rs = public_key(&state.decrypt_and_hash(&message[..DH_LEN + TAG_LEN])?)?;
+    state.mix_key(dh(&e, &rs));
+    state.decrypt_and_hash(&message[DH_LEN + TAG_LEN..])?;
+
+    let (receiver, sender) = state.split();
+    Ok(Transport {
+        sender,
+        receiver,
+        remote_static: rs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{dh, public_key, read_message, respond, write_message, HandshakeError, SymmetricState, DH_LEN, TAG_LEN};
+
+    // Needs the handshake internals to build the forgery, so it lives here
+    // rather than with the other secure tests.
+    #[tokio::test]
+    async fn respond_rejects_a_forged_static_key() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let victim = PublicKey::from(&StaticSecret::new(OsRng));
+        let attacker = StaticSecret::new(OsRng);
+        let server_static = StaticSecret::new(OsRng);
+
+        // Follows the initiator's steps, claiming the victim's static key
+        // without its secret half.
+        let forger = async {
+            let mut state = SymmetricState::new();
+            let e = StaticSecret::new(OsRng);
+            state.mix_hash(PublicKey::from(&e).as_bytes());
+            write_message(&mut client, PublicKey::from(&e).as_bytes()).await.unwrap();
+
+            let message = read_message(&mut client, DH_LEN * 2 + TAG_LEN * 2).await.unwrap();
+            let re = public_key(&message[..DH_LEN]).unwrap();
+            state.mix_hash(re.as_bytes());
+            state.mix_key(dh(&e, &re));
+            let rs = public_key(&state.decrypt_and_hash(&message[DH_LEN..DH_LEN * 2 + TAG_LEN]).unwrap()).unwrap();
+            state.mix_key(dh(&e, &rs));
+            state.decrypt_and_hash(&message[DH_LEN * 2 + TAG_LEN..]).unwrap();
+
+            let mut message = state.encrypt_and_hash(victim.as_bytes());
+            state.mix_key(dh(&attacker, &re));
+            message.extend(state.encrypt_and_hash(&[]));
+            write_message(&mut client, &message).await.unwrap();
+        };
+
+        let (result, _) = tokio::join!(respond(&mut server, &server_static), forger);
+        assert!(matches!(result, Err(HandshakeError::Decrypt)));
+    }
+    #[tokio::test]
+    async fn respond_rejects_a_low_order_key() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_message(&mut client, &[0u8; DH_LEN]).await.unwrap();
+        let result = respond(&mut server, &StaticSecret::new(OsRng)).await;
+        assert!(matches!(result, Err(HandshakeError::Malformed)));
+    }
+
+    #[test]
+    fn nonces_count_up_under_each_key() {
+        let mut state = SymmetricState::new();
+        state.mix_key(dh(&StaticSecret::new(OsRng), &PublicKey::from(&StaticSecret::new(OsRng))));
+        state.encrypt_and_hash(b"s");
+        state.encrypt_and_hash(&[]);
+        assert_eq!(state.n, 2);
+        state.mix_key(dh(&StaticSecret::new(OsRng), &PublicKey::from(&StaticSecret::new(OsRng))));
+        assert_eq!(state.n, 0);
+    }
+}
@@ -1,64 +1,501 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
 
-use ring::aead::chacha20_poly1305_openssh::TAG_LEN;
-use ring::aead::{NonceSequence, Nonce, NONCE_LEN, Algorithm, UnboundKey, SealingKey, BoundKey, Aad, OpeningKey, Tag};
+use ring::aead::{NonceSequence, Nonce, NONCE_LEN, Algorithm, UnboundKey, SealingKey, BoundKey, Aad, OpeningKey, LessSafeKey};
 use ring::error::Unspecified;
-use x25519_dalek::SharedSecret;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// How many counters behind the newest one `decrypt_at` still accepts.
+pub const REPLAY_WINDOW: u64 = 64;
+/// How many rekeys `decrypt_at` will ratchet through to open one message.
+pub const MAX_EPOCH_SKIP: u64 = 16;
+const REKEY_INFO: &[u8] = b"idms rekey";
+/// Plaintext bytes per chunk of a sealed stream; only the last is shorter.
+pub const STREAM_CHUNK: usize = 64 * 1024;
+pub const STREAM_SALT_LEN: usize = 32;
+const STREAM_INFO: &[u8] = b"idms stream";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptError {
+    /// A message with this counter was already opened.
+    Replayed,
+    /// The counter fell out of the replay window, or its epoch's key was
+    /// already ratcheted away.
+    TooOld,
+    /// The ciphertext failed to authenticate.
+    Unauthentic,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replayed => write!(f, "message was replayed"),
+            Self::TooOld => write!(f, "message is too old"),
+            Self::Unauthentic => write!(f, "message failed to authenticate"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    /// The stream ended before its last chunk.
+    Truncated,
+    /// A chunk header is out of range.
+    Malformed,
+    /// A chunk failed to authenticate, or came out of order.
+    Unauthentic,
+    /// The stream has more chunks than the counter can number.
+    TooLong,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "stream i/o failed: {}", err),
+            Self::Truncated => write!(f, "stream was truncated"),
+            Self::Malformed => write!(f, "malformed stream chunk"),
+            Self::Unauthentic => write!(f, "stream chunk failed to authenticate"),
+            Self::TooLong => write!(f, "stream is too long"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<io::Error> for StreamError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+/// A sliding window over the counters a receiver has accepted: the newest
+/// one, plus a bitmap of the `REPLAY_WINDOW` before it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplayWindow {
+    newest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn check(&self, counter: u64) -> Result<(), DecryptError> {
+        let newest = match self.newest {
+            Some(newest) if counter <= newest => newest,
+            _ => return Ok(()),
+        };
+        let age = newest - counter;
+        if age >= REPLAY_WINDOW {
+            Err(DecryptError::TooOld)
+        } else if self.seen & (1 << age) != 0 {
+            Err(DecryptError::Replayed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks `counter` as seen. Only call this once the message has
+    /// authenticated, or forgeries could push the window forward.
+    pub fn accept(&mut self, counter: u64) {
+        match self.newest {
+            Some(newest) if counter <= newest => self.seen |= 1 << (newest - counter),
+            Some(newest) => {
+                let shift = u32::try_from(counter - newest).unwrap_or(u32::MAX);
+                self.seen = self.seen.checked_shl(shift).unwrap_or(0) | 1;
+                self.newest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(counter);
+            }
+        }
+    }
+}
+
+/// Which end of a two-way context this is. Each end seals in its own nonce
+/// space and opens in the other's, so the two never reuse a nonce under
+/// their shared key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Self::Initiator => Self::Responder,
+            Self::Responder => Self::Initiator,
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Self::Initiator => 1,
+            Self::Responder => 2,
+        }
+    }
+}
+
+/// When to ratchet the key forward. Counters are split into epochs of
+/// `messages`, each under its own key, so both ends must agree on it. Once
+/// `bytes` of ciphertext have been sealed in an epoch, the sender skips to
+/// the start of the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            messages: 1 << 20,
+            bytes: 1 << 30,
+        }
+    }
+}
+
+/// One direction's key, and how far it has been ratcheted.
+#[derive(Clone)]
+struct Epoch {
+    index: u64,
+    key: [u8; 32],
+    /// Ciphertext carried so far under `key`.
+    bytes: u64,
+}
+
+impl Epoch {
+    fn new(key: [u8; 32]) -> Self {
+        Self { index: 0, key, bytes: 0 }
+    }
+
+    fn ratchet_to(&mut self, index: u64) {
+        while self.index < index {
+            let mut next = [0u8; 32];
+            Salt::new(HKDF_SHA256, &[])
+                .extract(&self.key)
+                .expand(&[REKEY_INFO], HKDF_SHA256)
+                .and_then(|okm| okm.fill(&mut next))
+                .unwrap();
+            self.key = next;
+            self.index += 1;
+            self.bytes = 0;
+        }
+    }
+}
+
+struct Sending {
+    counter: u64,
+    epoch: Epoch,
+}
+
+struct Receiving {
+    /// The counter `decrypt` expects next.
+    counter: u64,
+    epoch: Epoch,
+    window: ReplayWindow,
+}
+
+/// Clones share the counters, keys and replay window of both directions, so
+/// a reader task and a writer task can each hold one. Every counter is
+/// handed out once, even across threads.
 #[derive(Clone)]
 pub struct SymContext {
 
-    counter: Rc<RefCell<u32>>,
-    _alg: Rc<&'static Algorithm>,
-    _key: Rc<[u8; 32]>,
+    sending: Arc<Mutex<Sending>>,
+    receiving: Arc<Mutex<Receiving>>,
+    role: Option<Role>,
+    rekey: RekeyPolicy,
+    _alg: &'static Algorithm,
+    _key: [u8; 32],
 
 }
 
 impl SymContext {
+    /// A context for a key that only carries messages one way, such as a
+    /// key per direction or per message. Sealing and opening share one
+    /// nonce space.
     pub fn new(key: [u8; 32], alg: &'static Algorithm) -> Self {
         Self {
-            counter: Rc::new(RefCell::new(0u32)),
-            _key: Rc::new(key),
-            _alg: Rc::new(alg),
+            sending: Arc::new(Mutex::new(Sending { counter: 0, epoch: Epoch::new(key) })),
+            receiving: Arc::new(Mutex::new(Receiving {
+                counter: 0,
+                epoch: Epoch::new(key),
+                window: ReplayWindow::default(),
+            })),
+            role: None,
+            rekey: RekeyPolicy::default(),
+            _alg: alg,
+            _key: key,
+        }
+    }
+
+    /// A context for a key both ends send under. The peer must take the
+    /// other `Role`.
+    pub fn with_role(key: [u8; 32], alg: &'static Algorithm, role: Role) -> Self {
+        Self { role: Some(role), ..Self::new(key, alg) }
+    }
+
+    pub fn with_rekey(mut self, rekey: RekeyPolicy) -> Self {
+        self.rekey = rekey;
+        self
+    }
+
+    /// The counter the next sealed message gets. Counters are 64 bits wide
+    /// and never wrap; `encrypt` fails once they run out.
+    pub fn peek(&self) -> u64 {
+        self.sending.lock().unwrap().counter
+    }
+
+    /// How many times the sending key has been ratcheted.
+    pub fn epoch(&self) -> u64 {
+        self.sending.lock().unwrap().epoch.index
+    }
+
+    fn epoch_of(&self, counter: u64) -> u64 {
+        counter / self.rekey.messages.max(1)
+    }
+
+    fn tag(&self, sealing: bool) -> u8 {
+        match self.role {
+            Some(role) if sealing => role.tag(),
+            Some(role) => role.peer().tag(),
+            None => 0,
         }
     }
 
-    pub fn peek(&self) -> u32 {
-        self.counter.borrow().clone()
+    fn nonce(&self, counter: u64, sealing: bool) -> Nonce {
+        let tag = self.tag(sealing);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        nonce[NONCE_LEN - 1] = tag;
+        Nonce::assume_unique_for_key(nonce)
     }
 
-    pub fn key<T: BoundKey<Self>>(&self) -> T {
-        BoundKey::new(UnboundKey::new(*self._alg, self._key.as_ref()).unwrap(), self.clone())
+    /// Takes the next sending counter and ratchets to its epoch's key.
+    /// Refuses to wrap around to a counter already used.
+    fn reserve(&self, sending: &mut Sending) -> Result<u64, Unspecified> {
+        let counter = sending.counter;
+        sending.counter = counter.checked_add(1).ok_or(Unspecified)?;
+        sending.epoch.ratchet_to(self.epoch_of(counter));
+        Ok(counter)
     }
 
-    // !
-    // TODO: enable AAD;
-    // !
+    /// Counts `len` bytes against `epoch`, moving `next` up to the start of
+    /// the following epoch once the byte limit is reached.
+    fn count(&self, epoch: &mut Epoch, next: &mut u64, len: usize) {
+        epoch.bytes = epoch.bytes.saturating_add(len as u64);
+        if epoch.bytes >= self.rekey.bytes {
+            let start = (epoch.index + 1).saturating_mul(self.rekey.messages.max(1));
+            *next = (*next).max(start);
+        }
+    }
 
-    pub fn encrypt<'a>(&mut self, payload: &'a mut Vec<u8>) {
-        let mut bk: SealingKey<_> = self.key();
-        bk.seal_in_place_append_tag(Aad::empty(), payload).unwrap();
+    /// Seals `payload` in place and returns the counter it was sealed at,
+    /// for a receiver that opens it with `decrypt_at`. `aad` is
+    /// authenticated but not encrypted, and has to be passed again to open
+    /// the message. Fails once the counters run out, rather than reuse a
+    /// nonce.
+    pub fn encrypt(&mut self, aad: &[u8], payload: &mut Vec<u8>) -> Result<u64, Unspecified> {
+        // Seal under the lock, so that the counter returned is the one sealed
+        // with even if a clone is encrypting at the same time.
+        let mut sending = self.sending.lock().unwrap();
+        let sending = &mut *sending;
+        let counter = self.reserve(sending)?;
+        let unbound = UnboundKey::new(self._alg, &sending.epoch.key).unwrap();
+        SealingKey::new(unbound, At(self.nonce(counter, true)))
+            .seal_in_place_append_tag(Aad::from(aad), payload)?;
+        self.count(&mut sending.epoch, &mut sending.counter, payload.len());
+        Ok(counter)
     }
 
-    pub fn decrypt<'a>(&mut self, payload: &'a mut Vec<u8>) 
-        -> Result<&'a mut [u8], Unspecified> 
+    /// Opens the next message in order, following the sender's rekeys. A
+    /// message that fails to open doesn't use up the counter.
+    pub fn decrypt<'a>(&mut self, aad: &[u8], payload: &'a mut [u8])
+        -> Result<&'a mut [u8], Unspecified>
     {
-        let mut bk: OpeningKey<_> = self.key();
-        bk.open_in_place(Aad::empty(), payload)
+        let mut receiving = self.receiving.lock().unwrap();
+        let counter = receiving.counter;
+        let mut next = counter.checked_add(1).ok_or(Unspecified)?;
+        let mut epoch = receiving.epoch.clone();
+        epoch.ratchet_to(self.epoch_of(counter));
+
+        let len = payload.len();
+        let unbound = UnboundKey::new(self._alg, &epoch.key).unwrap();
+        let plaintext = OpeningKey::new(unbound, At(self.nonce(counter, false)))
+            .open_in_place(Aad::from(aad), payload)?;
+        self.count(&mut epoch, &mut next, len);
+        receiving.counter = next;
+        receiving.epoch = epoch;
+        Ok(plaintext)
+    }
+
+    /// Opens a message sealed at `counter`, for receivers that may see
+    /// messages out of order. Each counter is accepted once, and only while
+    /// it is within `REPLAY_WINDOW` of the newest one and its epoch's key
+    /// hasn't been ratcheted away.
+    pub fn decrypt_at<'a>(&mut self, counter: u64, aad: &[u8], payload: &'a mut [u8])
+        -> Result<&'a mut [u8], DecryptError>
+    {
+        // Hold the lock across the check and the accept, so that two clones
+        // can't both let the same counter through.
+        let mut receiving = self.receiving.lock().unwrap();
+        receiving.window.check(counter)?;
+        let index = self.epoch_of(counter);
+        if index < receiving.epoch.index {
+            return Err(DecryptError::TooOld);
+        }
+        if index - receiving.epoch.index > MAX_EPOCH_SKIP {
+            return Err(DecryptError::Unauthentic);
+        }
+
+        // Only ratchet for real once the message turns out to be genuine.
+        let mut epoch = receiving.epoch.clone();
+        epoch.ratchet_to(index);
+        let unbound = UnboundKey::new(self._alg, &epoch.key).unwrap();
+        let plaintext = OpeningKey::new(unbound, At(self.nonce(counter, false)))
+            .open_in_place(Aad::from(aad), payload)
+            .map_err(|_| DecryptError::Unauthentic)?;
+        receiving.epoch = epoch;
+        receiving.window.accept(counter);
+        Ok(plaintext)
     }
 }
 
-impl NonceSequence for SymContext {
+impl SymContext {
+    /// A key for one stream, so that streams never share nonces with each
+    /// other or with single messages.
+    fn stream_key(&self, salt: &[u8], sealing: bool) -> LessSafeKey {
+        let mut key = [0u8; 32];
+        Salt::new(HKDF_SHA256, salt)
+            .extract(&self._key)
+            .expand(&[STREAM_INFO, &[self.tag(sealing)]], HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key))
+            .unwrap();
+        LessSafeKey::new(UnboundKey::new(self._alg, &key).unwrap())
+    }
 
-    fn advance(&mut self) -> Result<Nonce, Unspecified> { // TODO: Just messy
-        let mut nonce = [0u8; NONCE_LEN];
-        for i in 0..4 {
-            nonce[i] = (*self.counter.borrow() << (i * 8) & 0xFF) as u8;
+    /// Seals everything `reader` yields into `writer`, one chunk in memory at
+    /// a time, and returns the plaintext length. Each chunk is framed as a
+    /// last-chunk flag, a length and the ciphertext; the flag and the chunk's
+    /// position are bound into its nonce.
+    pub async fn seal_stream<R, W>(&self, aad: &[u8], reader: &mut R, writer: &mut W)
+        -> Result<u64, StreamError>
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+    {
+        let mut salt = [0u8; STREAM_SALT_LEN];
+        SystemRandom::new().fill(&mut salt).unwrap();
+        let key = self.stream_key(&salt, true);
+        writer.write_all(&salt).await?;
+
+        let mut total = 0;
+        let mut counter = 0u32;
+        let mut chunk = read_chunk(reader).await?;
+        loop {
+            // Read one chunk ahead, to know whether this one is the last.
+            let next = if chunk.len() == STREAM_CHUNK {
+                read_chunk(reader).await?
+            } else {
+                Vec::new()
+            };
+            let last = next.is_empty();
+            total += chunk.len() as u64;
+
+            key.seal_in_place_append_tag(stream_nonce(counter, last), Aad::from(aad), &mut chunk)
+                .map_err(|_| StreamError::TooLong)?;
+            writer.write_u8(last as u8).await?;
+            writer.write_u32(chunk.len() as u32).await?;
+            writer.write_all(&chunk).await?;
+            if last {
+                break;
+            }
+            counter = counter.checked_add(1).ok_or(StreamError::TooLong)?;
+            chunk = next;
         }
-        *(*self.counter).borrow_mut() += 1;
-        Ok(Nonce::assume_unique_for_key(nonce))
+        writer.flush().await?;
+        Ok(total)
     }
 
+    /// Opens a stream from `seal_stream` into `writer` and returns the
+    /// plaintext length. Chunks are written as they authenticate, so on an
+    /// error whatever was written so far must be thrown away.
+    pub async fn open_stream<R, W>(&self, aad: &[u8], reader: &mut R, writer: &mut W)
+        -> Result<u64, StreamError>
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+    {
+        let mut salt = [0u8; STREAM_SALT_LEN];
+        reader.read_exact(&mut salt).await?;
+        let key = self.stream_key(&salt, false);
+        let full = STREAM_CHUNK + self._alg.tag_len();
+
+        let mut total = 0;
+        let mut counter = 0u32;
+        let mut chunk = Vec::with_capacity(full);
+        loop {
+            let last = match reader.read_u8().await? {
+                0 => false,
+                1 => true,
+                _ => return Err(StreamError::Malformed),
+            };
+            let len = reader.read_u32().await? as usize;
+            if len > full || len < self._alg.tag_len() || (!last && len != full) {
+                return Err(StreamError::Malformed);
+            }
+            chunk.resize(len, 0);
+            reader.read_exact(&mut chunk).await?;
+
+            let plaintext = key
+                .open_in_place(stream_nonce(counter, last), Aad::from(aad), &mut chunk)
+                .map_err(|_| StreamError::Unauthentic)?;
+            writer.write_all(plaintext).await?;
+            total += plaintext.len() as u64;
+            if last {
+                break;
+            }
+            counter = counter.checked_add(1).ok_or(StreamError::TooLong)?;
+        }
+        writer.flush().await?;
+        Ok(total)
+    }
+}
+
+/// Fills a chunk from `reader`, stopping short only at the end of input.
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut chunk = vec![0u8; STREAM_CHUNK];
+    let mut filled = 0;
+    while filled < STREAM_CHUNK {
+        match reader.read(&mut chunk[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    chunk.truncate(filled);
+    Ok(chunk)
+}
+
+fn stream_nonce(counter: u32, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..4].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
 }
 
+/// A nonce that has already been chosen.
+struct At(Nonce);
+
+impl NonceSequence for At {
+
+    fn advance(&mut self) -> Result<Nonce, Unspecified> {
+        Ok(Nonce::assume_unique_for_key(*self.0.as_ref()))
+    }
+
+}
@@ -0,0 +1,87 @@
+use rand_core::OsRng;
+use ring::error::Unspecified;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::signature::{UnparsedPublicKey, ED25519, ED25519_PUBLIC_KEY_LEN};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::keys::SharedKey;
+
+const X3DH_INFO: &[u8] = b"idms x3dh";
+
+/// What a recipient leaves with the server so that others can start a
+/// session while it is offline. The signed prekey is signed with the
+/// recipient's Ed25519 key; the one-time prekey, if any are left, is handed
+/// out to one sender only.
+#[derive(Debug, Clone)]
+pub struct PreKeyBundle {
+    pub identity_key: PublicKey,
+    pub signing_key: [u8; ED25519_PUBLIC_KEY_LEN],
+    pub signed_prekey: PublicKey,
+    pub prekey_signature: Vec<u8>,
+    pub one_time_prekey: Option<PublicKey>,
+}
+
+impl PreKeyBundle {
+    pub fn verify(&self) -> bool {
+        UnparsedPublicKey::new(&ED25519, &self.signing_key)
+            .verify(self.signed_prekey.as_bytes(), &self.prekey_signature)
+            .is_ok()
+    }
+}
+
+/// The sender's side: checks the bundle's signature and derives the shared
+/// key. The ephemeral key returned has to reach the recipient with the first
+/// message, along with which one-time prekey was used.
+pub fn initiate(identity: &StaticSecret, bundle: &PreKeyBundle) -> Result<(SharedKey, PublicKey), Unspecified> {
+    if !bundle.verify() {
+        return Err(Unspecified);
+    }
+    let ephemeral = StaticSecret::new(OsRng);
+    let mut dh = vec![
+        identity.diffie_hellman(&bundle.signed_prekey).to_bytes(),
+        ephemeral.diffie_hellman(&bundle.identity_key).to_bytes(),
+        ephemeral.diffie_hellman(&bundle.signed_prekey).to_bytes(),
+    ];
+    if let Some(one_time_prekey) = &bundle.one_time_prekey {
+        dh.push(ephemeral.diffie_hellman(one_time_prekey).to_bytes());
+    }
+
+    let shared = SharedKey::schedule(kdf(&dh), PublicKey::from(identity), bundle.identity_key);
+    Ok((shared, PublicKey::from(&ephemeral)))
+}
+
+/// The recipient's side, from the secrets behind its bundle and what the
+/// sender passed along.
+pub fn respond(
+    identity: &StaticSecret,
+    signed_prekey: &StaticSecret,
+    one_time_prekey: Option<&StaticSecret>,
+    remote_identity: &PublicKey,
+    ephemeral: &PublicKey,
+) -> SharedKey {
+    let mut dh = vec![
+        signed_prekey.diffie_hellman(remote_identity).to_bytes(),
+        identity.diffie_hellman(ephemeral).to_bytes(),
+        signed_prekey.diffie_hellman(ephemeral).to_bytes(),
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh.push(one_time_prekey.diffie_hellman(ephemeral).to_bytes());
+    }
+    SharedKey::schedule(kdf(&dh), PublicKey::from(identity), *remote_identity)
+}
+
+fn kdf(dh: &[[u8; 32]]) -> [u8; 32] {
+    // As in X3DH, 32 0xFF bytes go first to set these apart from X25519
+    // outputs used anywhere else.
+    let mut ikm = vec![0xFF; 32];
+    for output in dh {
+        ikm.extend_from_slice(output);
+    }
+    let mut secret = [0u8; 32];
+    Salt::new(HKDF_SHA256, &[0u8; 32])
+        .extract(&ikm)
+        .expand(&[X3DH_INFO], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut secret))
+        .unwrap();
+    secret
+}
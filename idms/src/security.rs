@@ -1,64 +1,834 @@
-use serde::{Serialize, Deserialize};
-use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use idms::secure::sym::DecryptError;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::signature::{UnparsedPublicKey, ED25519, ED25519_PUBLIC_KEY_LEN};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::hash::Hash;
+use std::io;
+use std::time::{Duration, SystemTime};
+use x25519_dalek::{PublicKey, StaticSecret};
 
+pub const SHARED_KEY_LENGTH: usize = 32;
+pub const RESUME_NONCE_LEN: usize = 32;
+const RESUMPTION_INFO: &[u8] = b"idms resumption";
+const RESUME_INFO: &[u8] = b"idms resume";
+/// How long a replaced keychain keeps verifying after a rotation.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
 
+#[derive(Clone)]
 pub struct ForeignKeychain {
     pub public_key: PublicKey,
-    pub shared_key: SharedSecret,
+    /// Raw bytes rather than a `SharedSecret` so that stores can persist it.
+    pub shared_key: [u8; SHARED_KEY_LENGTH],
+    /// The user's long-term Ed25519 key, registered at `Sync`.
+    pub signing_key: Option<[u8; ED25519_PUBLIC_KEY_LEN]>,
+    /// Starts at 1 and goes up by one with every rotation.
+    pub version: u32,
+    pub created_at: SystemTime,
+    /// `None` keeps the keychain valid until it is replaced.
+    pub expires_at: Option<SystemTime>,
 }
 
 impl ForeignKeychain {
     pub fn new(public_key: PublicKey, static_secret: StaticSecret) -> Self {
         Self {
-            public_key: public_key,
-            shared_key: static_secret.diffie_hellman(&public_key),
+            public_key,
+            shared_key: static_secret.diffie_hellman(&public_key).to_bytes(),
+            signing_key: None,
+            version: 1,
+            created_at: SystemTime::now(),
+            expires_at: None,
         }
     }
+
+    pub fn with_signing_key(mut self, signing_key: [u8; ED25519_PUBLIC_KEY_LEN]) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.expires_at = Some(self.created_at + lifetime);
+        self
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Shortens the expiry so that a replaced keychain lasts at most `grace`
+    /// longer.
+    pub fn retire(mut self, grace: Duration) -> Self {
+        let deadline = SystemTime::now() + grace;
+        self.expires_at = Some(self.expires_at.map_or(deadline, |at| at.min(deadline)));
+        self
+    }
+
+    /// Checks an Ed25519 `signature` over `message` against the registered
+    /// signing key. Keychains without one verify nothing.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.signing_key {
+            Some(key) => UnparsedPublicKey::new(&ED25519, key)
+                .verify(message, signature)
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// What a session ticket resumes from. Both ends derive it from the shared
+/// key the ticket was issued under, so it never goes over the wire.
+pub fn resumption_secret(shared_key: &[u8; SHARED_KEY_LENGTH]) -> [u8; 32] {
+    hkdf(&[], shared_key, RESUMPTION_INFO)
 }
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+/// The shared key a `Resume` moves to. The client's fresh nonce makes it
+/// new every time.
+pub fn resumed_key(secret: &[u8; 32], nonce: &[u8]) -> [u8; SHARED_KEY_LENGTH] {
+    hkdf(nonce, secret, RESUME_INFO)
+}
+
+/// Keys the `proof` in a `Resume`: an HMAC over its `header()`, ticket and
+/// nonce, which only the holder of the ticket's secret can make.
+pub fn resume_proof_key(secret: &[u8; 32]) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret)
+}
+
+fn hkdf(salt: &[u8], secret: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Salt::new(HKDF_SHA256, salt)
+        .extract(secret)
+        .expand(&[info], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .unwrap();
+    key
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum SealedMessage<'a> {
+    /// Either way: a heartbeat. The guard answers each with one of its own.
     Nil,
+    /// Client to guard: sign in with the password and replace the
+    /// keychain. The old one keeps verifying for a grace period, unless
+    /// `revoke` is set, e.g. because its signing key leaked.
     Sync {
         userid: &'a str,
         password: &'a str,
-        public_key: &'a[u8],
+        public_key: &'a [u8],
+        signing_key: &'a [u8],
+        revoke: bool,
     },
+    /// Client to guard: `signature` covers this message's `header()`
+    /// followed by `message`. `counter` goes up by one with every message
+    /// the sender signs under its keychain, so none can be passed twice.
     Communicate {
         userid: &'a str,
-        signature: &'a[u8],
-        message: &'a[u8],
+        counter: u64,
+        signature: &'a [u8],
+        message: &'a [u8],
     },
+    /// Client to guard: `message` is sealed to the guard's public key, with
+    /// this message's `header()` as associated data. `sent_at` is in Unix
+    /// seconds; the guard opens each box once, and only close to then.
     RedBox {
         userid: &'a str,
-        message: &'a[u8],
+        sent_at: u64,
+        message: &'a [u8],
+    },
+    /// Creates a new user. `Sync` only re-keys users that already exist.
+    Register {
+        userid: &'a str,
+        password: &'a str,
+        public_key: &'a [u8],
+        signing_key: &'a [u8],
+    },
+    /// Guard to client: the last `Register` or `Sync` went through.
+    Ack { userid: &'a str },
+    /// Guard to client: a message was turned down, and why.
+    Reject { userid: &'a str, reason: &'a str },
+    /// Guard to client: this connection's `Sync`s are refused for the next
+    /// `seconds` after too many wrong passwords on it. A locked account
+    /// gets a plain `Reject`, as a wrong password does.
+    Locked { userid: &'a str, seconds: u64 },
+    /// Client to guard: a new signed prekey, plus one-time prekeys to add
+    /// to the pool, concatenated. `prekey_signature` covers the signed
+    /// prekey and `signature` covers it followed by the one-time prekeys,
+    /// both under the user's Ed25519 key.
+    PublishPreKeys {
+        userid: &'a str,
+        signed_prekey: &'a [u8],
+        prekey_signature: &'a [u8],
+        one_time_prekeys: &'a [u8],
+        signature: &'a [u8],
+    },
+    /// Client to guard: asks for a bundle to start a session with `subject`
+    /// while it is offline. `userid` has to be signed in on the connection.
+    FetchPreKeys { userid: &'a str, subject: &'a str },
+    /// Guard to client: what X3DH needs to start a session with `subject`.
+    /// `one_time_prekey` is empty once the pool has run dry.
+    PreKeyBundle {
+        userid: &'a str,
+        subject: &'a str,
+        identity_key: &'a [u8],
+        signing_key: &'a [u8],
+        signed_prekey: &'a [u8],
+        prekey_signature: &'a [u8],
+        one_time_prekey: &'a [u8],
+    },
+    /// Guard to client: only `remaining` one-time prekeys are left, so
+    /// publish some more.
+    PreKeysLow { userid: &'a str, remaining: u32 },
+    /// Client to guard: `message` for `recipient`, held until they collect
+    /// it. `signature` covers this message's `header()` followed by
+    /// `message`, so it can't be readdressed. `counter` is drawn from the
+    /// same count as `Communicate`'s, so neither can be passed twice.
+    Forward {
+        userid: &'a str,
+        recipient: &'a str,
+        counter: u64,
+        signature: &'a [u8],
+        message: &'a [u8],
+    },
+    /// Guard to client: a message `sender` forwarded, with their signature
+    /// and the `counter` it was signed with. It is held, and may arrive
+    /// again, until acknowledged by `id`.
+    Mail {
+        userid: &'a str,
+        id: u64,
+        sender: &'a str,
+        counter: u64,
+        signature: &'a [u8],
+        message: &'a [u8],
+    },
+    /// Client to guard: `Mail` `id` arrived and can be dropped.
+    MailAck { userid: &'a str, id: u64 },
+    /// Client to guard: where `subject` stands now, answered with `Presence`.
+    /// Rejected until `subject` has forwarded `userid` mail.
+    QueryPresence { userid: &'a str, subject: &'a str },
+    /// Client to guard: like `QueryPresence`, then a `Presence` on every
+    /// change for as long as `userid` stays signed in.
+    WatchPresence { userid: &'a str, subject: &'a str },
+    /// Guard to client: `subject` is offline (0), idle (1) or online (2).
+    Presence {
+        userid: &'a str,
+        subject: &'a str,
+        status: u8,
+    },
+    /// Guard to client: lets the next connection `Resume` instead of
+    /// `Sync`, once, within `seconds`. Opaque to the client.
+    Ticket {
+        userid: &'a str,
+        ticket: &'a [u8],
+        seconds: u64,
+    },
+    /// Client to guard: signs in with a `Ticket` and moves to the shared
+    /// key `resumed_key` gives for `nonce`. `proof` is keyed by
+    /// `resume_proof_key`.
+    Resume {
+        userid: &'a str,
+        ticket: &'a [u8],
+        nonce: &'a [u8],
+        proof: &'a [u8],
+    },
+}
+
+impl SealedMessage<'_> {
+    /// The kind of message, who it is for, and whatever else has to be
+    /// bound to its payload: the recipient, counter or send time. Sealed
+    /// payloads use this as associated data, so a ciphertext can't be moved
+    /// to another user or kind of message.
+    pub fn header(&self) -> Vec<u8> {
+        let (kind, userid, extra): (u8, &str, &[u8]) = match *self {
+            Self::Nil => (0, "", &[]),
+            Self::Sync { userid, .. } => (1, userid, &[]),
+            Self::Communicate { userid, .. } => (2, userid, &[]),
+            Self::RedBox { userid, .. } => (3, userid, &[]),
+            Self::Register { userid, .. } => (4, userid, &[]),
+            Self::Ack { userid } => (5, userid, &[]),
+            Self::Reject { userid, .. } => (6, userid, &[]),
+            Self::Locked { userid, .. } => (7, userid, &[]),
+            Self::PublishPreKeys { userid, .. } => (8, userid, &[]),
+            Self::FetchPreKeys { userid, .. } => (9, userid, &[]),
+            Self::PreKeyBundle { userid, .. } => (10, userid, &[]),
+            Self::PreKeysLow { userid, .. } => (11, userid, &[]),
+            Self::Forward {
+                userid, recipient, ..
+            } => (12, userid, recipient.as_bytes()),
+            Self::Mail { userid, .. } => (13, userid, &[]),
+            Self::MailAck { userid, .. } => (14, userid, &[]),
+            Self::QueryPresence { userid, .. } => (15, userid, &[]),
+            Self::WatchPresence { userid, .. } => (16, userid, &[]),
+            Self::Presence { userid, .. } => (17, userid, &[]),
+            Self::Ticket { userid, .. } => (18, userid, &[]),
+            Self::Resume { userid, .. } => (19, userid, &[]),
+        };
+
+        let mut header = vec![kind];
+        header.extend((userid.len() as u32).to_be_bytes());
+        header.extend(userid.as_bytes());
+        header.extend(extra);
+        match *self {
+            Self::Communicate { counter, .. } | Self::Forward { counter, .. } => {
+                header.extend(counter.to_be_bytes())
+            }
+            Self::RedBox { sent_at, .. } => header.extend(sent_at.to_be_bytes()),
+            _ => {}
+        }
+        header
+    }
+}
+
+/// Owned counterpart of [`SealedMessage`], for messages that have to outlive
+/// the buffer they were read from. Both encode to the same bytes.
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OwnedSealedMessage {
+    Nil,
+    Sync {
+        userid: String,
+        password: String,
+        public_key: Vec<u8>,
+        signing_key: Vec<u8>,
+        revoke: bool,
+    },
+    Communicate {
+        userid: String,
+        counter: u64,
+        signature: Vec<u8>,
+        message: Vec<u8>,
+    },
+    RedBox {
+        userid: String,
+        sent_at: u64,
+        message: Vec<u8>,
+    },
+    Register {
+        userid: String,
+        password: String,
+        public_key: Vec<u8>,
+        signing_key: Vec<u8>,
+    },
+    Ack {
+        userid: String,
+    },
+    Reject {
+        userid: String,
+        reason: String,
+    },
+    Locked {
+        userid: String,
+        seconds: u64,
+    },
+    PublishPreKeys {
+        userid: String,
+        signed_prekey: Vec<u8>,
+        prekey_signature: Vec<u8>,
+        one_time_prekeys: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    FetchPreKeys {
+        userid: String,
+        subject: String,
+    },
+    PreKeyBundle {
+        userid: String,
+        subject: String,
+        identity_key: Vec<u8>,
+        signing_key: Vec<u8>,
+        signed_prekey: Vec<u8>,
+        prekey_signature: Vec<u8>,
+        one_time_prekey: Vec<u8>,
+    },
+    PreKeysLow {
+        userid: String,
+        remaining: u32,
+    },
+    Forward {
+        userid: String,
+        recipient: String,
+        counter: u64,
+        signature: Vec<u8>,
+        message: Vec<u8>,
+    },
+    Mail {
+        userid: String,
+        id: u64,
+        sender: String,
+        counter: u64,
+        signature: Vec<u8>,
+        message: Vec<u8>,
+    },
+    MailAck {
+        userid: String,
+        id: u64,
+    },
+    QueryPresence {
+        userid: String,
+        subject: String,
     },
+    WatchPresence {
+        userid: String,
+        subject: String,
+    },
+    Presence {
+        userid: String,
+        subject: String,
+        status: u8,
+    },
+    Ticket {
+        userid: String,
+        ticket: Vec<u8>,
+        seconds: u64,
+    },
+    Resume {
+        userid: String,
+        ticket: Vec<u8>,
+        nonce: Vec<u8>,
+        proof: Vec<u8>,
+    },
+}
+
+impl OwnedSealedMessage {
+    pub fn as_sealed(&self) -> SealedMessage<'_> {
+        match self {
+            Self::Nil => SealedMessage::Nil,
+            Self::Sync {
+                userid,
+                password,
+                public_key,
+                signing_key,
+                revoke,
+            } => SealedMessage::Sync {
+                userid,
+                password,
+                public_key,
+                signing_key,
+                revoke: *revoke,
+            },
+            Self::Communicate {
+                userid,
+                counter,
+                signature,
+                message,
+            } => SealedMessage::Communicate {
+                userid,
+                counter: *counter,
+                signature,
+                message,
+            },
+            Self::RedBox {
+                userid,
+                sent_at,
+                message,
+            } => SealedMessage::RedBox {
+                userid,
+                sent_at: *sent_at,
+                message,
+            },
+            Self::Register {
+                userid,
+                password,
+                public_key,
+                signing_key,
+            } => SealedMessage::Register {
+                userid,
+                password,
+                public_key,
+                signing_key,
+            },
+            Self::Ack { userid } => SealedMessage::Ack { userid },
+            Self::Reject { userid, reason } => SealedMessage::Reject { userid, reason },
+            Self::Locked { userid, seconds } => SealedMessage::Locked {
+                userid,
+                seconds: *seconds,
+            },
+            Self::PublishPreKeys {
+                userid,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekeys,
+                signature,
+            } => SealedMessage::PublishPreKeys {
+                userid,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekeys,
+                signature,
+            },
+            Self::FetchPreKeys { userid, subject } => {
+                SealedMessage::FetchPreKeys { userid, subject }
+            }
+            Self::PreKeyBundle {
+                userid,
+                subject,
+                identity_key,
+                signing_key,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekey,
+            } => SealedMessage::PreKeyBundle {
+                userid,
+                subject,
+                identity_key,
+                signing_key,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekey,
+            },
+            Self::PreKeysLow { userid, remaining } => SealedMessage::PreKeysLow {
+                userid,
+                remaining: *remaining,
+            },
+            Self::Forward {
+                userid,
+                recipient,
+                counter,
+                signature,
+                message,
+            } => SealedMessage::Forward {
+                userid,
+                recipient,
+                counter: *counter,
+                signature,
+                message,
+            },
+            Self::Mail {
+                userid,
+                id,
+                sender,
+                counter,
+                signature,
+                message,
+            } => SealedMessage::Mail {
+                userid,
+                id: *id,
+                sender,
+                counter: *counter,
+                signature,
+                message,
+            },
+            Self::MailAck { userid, id } => SealedMessage::MailAck { userid, id: *id },
+            Self::QueryPresence { userid, subject } => {
+                SealedMessage::QueryPresence { userid, subject }
+            }
+            Self::WatchPresence { userid, subject } => {
+                SealedMessage::WatchPresence { userid, subject }
+            }
+            Self::Presence {
+                userid,
+                subject,
+                status,
+            } => SealedMessage::Presence {
+                userid,
+                subject,
+                status: *status,
+            },
+            Self::Ticket {
+                userid,
+                ticket,
+                seconds,
+            } => SealedMessage::Ticket {
+                userid,
+                ticket,
+                seconds: *seconds,
+            },
+            Self::Resume {
+                userid,
+                ticket,
+                nonce,
+                proof,
+            } => SealedMessage::Resume {
+                userid,
+                ticket,
+                nonce,
+                proof,
+            },
+        }
+    }
+}
+
+impl From<SealedMessage<'_>> for OwnedSealedMessage {
+    fn from(message: SealedMessage<'_>) -> Self {
+        match message {
+            SealedMessage::Nil => Self::Nil,
+            SealedMessage::Sync {
+                userid,
+                password,
+                public_key,
+                signing_key,
+                revoke,
+            } => Self::Sync {
+                userid: userid.to_owned(),
+                password: password.to_owned(),
+                public_key: public_key.to_vec(),
+                signing_key: signing_key.to_vec(),
+                revoke,
+            },
+            SealedMessage::Communicate {
+                userid,
+                counter,
+                signature,
+                message,
+            } => Self::Communicate {
+                userid: userid.to_owned(),
+                counter,
+                signature: signature.to_vec(),
+                message: message.to_vec(),
+            },
+            SealedMessage::RedBox {
+                userid,
+                sent_at,
+                message,
+            } => Self::RedBox {
+                userid: userid.to_owned(),
+                sent_at,
+                message: message.to_vec(),
+            },
+            SealedMessage::Register {
+                userid,
+                password,
+                public_key,
+                signing_key,
+            } => Self::Register {
+                userid: userid.to_owned(),
+                password: password.to_owned(),
+                public_key: public_key.to_vec(),
+                signing_key: signing_key.to_vec(),
+            },
+            SealedMessage::Ack { userid } => Self::Ack {
+                userid: userid.to_owned(),
+            },
+            SealedMessage::Reject { userid, reason } => Self::Reject {
+                userid: userid.to_owned(),
+                reason: reason.to_owned(),
+            },
+            SealedMessage::Locked { userid, seconds } => Self::Locked {
+                userid: userid.to_owned(),
+                seconds,
+            },
+            SealedMessage::PublishPreKeys {
+                userid,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekeys,
+                signature,
+            } => Self::PublishPreKeys {
+                userid: userid.to_owned(),
+                signed_prekey: signed_prekey.to_vec(),
+                prekey_signature: prekey_signature.to_vec(),
+                one_time_prekeys: one_time_prekeys.to_vec(),
+                signature: signature.to_vec(),
+            },
+            SealedMessage::FetchPreKeys { userid, subject } => Self::FetchPreKeys {
+                userid: userid.to_owned(),
+                subject: subject.to_owned(),
+            },
+            SealedMessage::PreKeyBundle {
+                userid,
+                subject,
+                identity_key,
+                signing_key,
+                signed_prekey,
+                prekey_signature,
+                one_time_prekey,
+            } => Self::PreKeyBundle {
+                userid: userid.to_owned(),
+                subject: subject.to_owned(),
+                identity_key: identity_key.to_vec(),
+                signing_key: signing_key.to_vec(),
+                signed_prekey: signed_prekey.to_vec(),
+                prekey_signature: prekey_signature.to_vec(),
+                one_time_prekey: one_time_prekey.to_vec(),
+            },
+            SealedMessage::PreKeysLow { userid, remaining } => Self::PreKeysLow {
+                userid: userid.to_owned(),
+                remaining,
+            },
+            SealedMessage::Forward {
+                userid,
+                recipient,
+                counter,
+                signature,
+                message,
+            } => Self::Forward {
+                userid: userid.to_owned(),
+                recipient: recipient.to_owned(),
+                counter,
+                signature: signature.to_vec(),
+                message: message.to_vec(),
+            },
+            SealedMessage::Mail {
+                userid,
+                id,
+                sender,
+                counter,
+                signature,
+                message,
+            } => Self::Mail {
+                userid: userid.to_owned(),
+                id,
+                sender: sender.to_owned(),
+                counter,
+                signature: signature.to_vec(),
+                message: message.to_vec(),
+            },
+            SealedMessage::MailAck { userid, id } => Self::MailAck {
+                userid: userid.to_owned(),
+                id,
+            },
+            SealedMessage::QueryPresence { userid, subject } => Self::QueryPresence {
+                userid: userid.to_owned(),
+                subject: subject.to_owned(),
+            },
+            SealedMessage::WatchPresence { userid, subject } => Self::WatchPresence {
+                userid: userid.to_owned(),
+                subject: subject.to_owned(),
+            },
+            SealedMessage::Presence {
+                userid,
+                subject,
+                status,
+            } => Self::Presence {
+                userid: userid.to_owned(),
+                subject: subject.to_owned(),
+                status,
+            },
+            SealedMessage::Ticket {
+                userid,
+                ticket,
+                seconds,
+            } => Self::Ticket {
+                userid: userid.to_owned(),
+                ticket: ticket.to_vec(),
+                seconds,
+            },
+            SealedMessage::Resume {
+                userid,
+                ticket,
+                nonce,
+                proof,
+            } => Self::Resume {
+                userid: userid.to_owned(),
+                ticket: ticket.to_vec(),
+                nonce: nonce.to_vec(),
+                proof: proof.to_vec(),
+            },
+        }
+    }
 }
 
-pub enum EncryptionData<'a> {
+pub enum EncryptionData {
     Passed {
-        encrypted: bool,
-        username: &'a str,
-        sharedkey: &'a SharedSecret,
+        username: String,
+    },
+    /// Decrypted with the guard's key, but the sender never proved who they
+    /// are; `username` is only what the message claimed.
+    Anonymous {
+        username: String,
+    },
+    /// Signed with a keychain that has expired. The client has to `Sync` a
+    /// fresh key before the guard will pass its messages again.
+    Expired {
+        username: String,
+        version: u32,
     },
+    /// `encrypted` tells a red box that didn't open from a `Communicate`
+    /// that didn't verify.
     Failed {
-        target: &'a str,
+        target: String,
         encrypted: bool,
     },
+    /// Genuine, but already passed once, or too old to tell.
+    Replayed {
+        username: String,
+        error: DecryptError,
+    },
 }
 
-pub struct DecodedMessage<'a> {
-    pub encryption_data: EncryptionData<'a>,
-    pub message: &'a [u8],
+pub struct DecodedMessage {
+    pub encryption_data: EncryptionData,
+    pub message: Vec<u8>,
 }
 
+#[derive(Debug)]
+pub enum KeyStoreError {
+    NotFound,
+    /// A key already exists where a new one was expected.
+    Conflict,
+    Io(io::Error),
+    /// Stored data failed to decode or authenticate.
+    Corrupt(String),
+}
 
-pub trait KeyStore {
+impl fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no key stored for this id"),
+            Self::Conflict => write!(f, "a key is already stored for this id"),
+            Self::Io(err) => write!(f, "key store i/o failed: {}", err),
+            Self::Corrupt(reason) => write!(f, "key store is corrupt: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for KeyStoreError {}
+
+impl From<io::Error> for KeyStoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
 
+pub trait KeyStore {
     type ID: Hash;
 
-    fn set_key(&mut self, id: Self::ID, keychain: ForeignKeychain) -> Result<Option<ForeignKeychain>, ()>;
-    fn get_key(&mut self, id: Self::ID) -> Option<&ForeignKeychain>;
+    /// Stores `keychain`, returning the one it replaced. Stores that keep
+    /// history hold on to the replaced keychain for `get_previous_key`.
+    async fn set_key(
+        &mut self,
+        id: Self::ID,
+        keychain: ForeignKeychain,
+    ) -> Result<Option<ForeignKeychain>, KeyStoreError>;
+
+    async fn get_key(&self, id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError>;
+
+    /// The keychain most recently replaced for `id`, while it is still
+    /// within its grace period. Stores that keep no history find nothing.
+    async fn get_previous_key(&self, _id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError> {
+        Err(KeyStoreError::NotFound)
+    }
+
+    /// Replaces the current keychain with `keychain` one version on, or
+    /// stores it as version 1 if there was none.
+    async fn rotate_key(
+        &mut self,
+        id: Self::ID,
+        mut keychain: ForeignKeychain,
+    ) -> Result<Option<ForeignKeychain>, KeyStoreError> {
+        keychain.version = match self.get_key(&id).await {
+            Ok(current) => current.version + 1,
+            Err(KeyStoreError::NotFound) => 1,
+            Err(err) => return Err(err),
+        };
+        self.set_key(id, keychain).await
+    }
+
+    /// Forgets the keychain `rotate_key` last replaced for `id`, so that it
+    /// stops verifying before its grace period is up. Stores that keep no
+    /// history have nothing to forget.
+    async fn revoke_previous_key(&mut self, _id: &Self::ID) -> Result<(), KeyStoreError> {
+        Ok(())
+    }
 
-}
\ No newline at end of file
+    /// Like `set_key`, but fails with `Conflict` instead of replacing a key.
+    async fn insert_key(
+        &mut self,
+        id: Self::ID,
+        keychain: ForeignKeychain,
+    ) -> Result<(), KeyStoreError> {
+        match self.get_key(&id).await {
+            Ok(_) => Err(KeyStoreError::Conflict),
+            Err(KeyStoreError::NotFound) => self.set_key(id, keychain).await.map(|_| ()),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,865 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime};
+
+use idms::secure::secure_channel;
+use rand_core::OsRng;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinSet;
+use x25519_dalek::StaticSecret;
+
+use crate::codec::{CodecError, FrameReader, FrameWriter};
+use crate::limit::RateLimit;
+use crate::mailbox::{MailPolicy, MailStore};
+use crate::prekey::PreKeyStore;
+use crate::presence::{KeepalivePolicy, PresenceRegistry};
+use crate::replay::SeenMessages;
+use crate::security::{
+    DecodedMessage, EncryptionData, ForeignKeychain, KeyStore, KeyStoreError, DEFAULT_GRACE_PERIOD,
+};
+use crate::store::{FileKeyStore, RecordFile, StoreError, MASTER_KEY_LENGTH};
+use crate::ticket::{TicketKeeper, DEFAULT_TICKET_LIFETIME};
+use crate::user::{
+    CredentialStore, LockoutPolicy, PasswordHash, RegistryError, User, UserRegistry, UserStatus,
+    UserStore,
+};
+use crate::{SocketGuard, MAX_REGISTRATIONS, REGISTRATION_WINDOW};
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+const INBOUND_QUEUE: usize = 32;
+const GUARD_KEY_ID: &str = "guard";
+
+/// Where the server accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// `unix:<path>` is a Unix socket, anything else a TCP address.
+    pub fn parse(addr: &str) -> Self {
+        match addr.strip_prefix("unix:") {
+            Some(path) => Self::Unix(PathBuf::from(path)),
+            None => Self::Tcp(addr.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    MissingMasterKey,
+    BadMasterKey,
+    /// The named variable is set but isn't a whole number.
+    BadNumber(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingMasterKey => write!(f, "IDMS_MASTER_KEY is not set"),
+            Self::BadMasterKey => write!(
+                f,
+                "IDMS_MASTER_KEY must be {} hex characters",
+                MASTER_KEY_LENGTH * 2
+            ),
+            Self::BadNumber(name) => write!(f, "{} must be a whole number", name),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+pub struct Config {
+    pub listen: Listen,
+    /// Holds the guard key and the key, user, prekey, mail and replay stores.
+    pub data_dir: PathBuf,
+    pub master_key: [u8; MASTER_KEY_LENGTH],
+    pub lockout: LockoutPolicy,
+    pub mail: MailPolicy,
+    pub keepalive: KeepalivePolicy,
+    /// How long a session ticket can be resumed with.
+    pub ticket_lifetime: Duration,
+    /// How long a keychain lasts before the client has to `Sync` a new one.
+    /// `None` keeps it until it is replaced.
+    pub key_lifetime: Option<Duration>,
+    /// How long a replaced keychain still verifies messages.
+    pub key_grace_period: Duration,
+}
+
+impl Config {
+    /// Reads the listen address from the first argument or `IDMS_LISTEN`,
+    /// the data directory from `IDMS_DATA_DIR` and the hex-encoded master
+    /// key from `IDMS_MASTER_KEY`. `IDMS_MAX_FAILURES` and
+    /// `IDMS_LOCKOUT_SECS` override the default lockout policy, and
+    /// `IDMS_MAILBOX_MESSAGES`, `IDMS_MAILBOX_BYTES` and
+    /// `IDMS_MAILBOX_TTL_SECS` the default mailbox quota. `IDMS_IDLE_SECS`
+    /// and `IDMS_TIMEOUT_SECS` set how long a silent connection lasts,
+    /// `IDMS_TICKET_SECS` how long a session ticket does, and
+    /// `IDMS_KEY_LIFETIME_SECS` and `IDMS_KEY_GRACE_SECS` how long a
+    /// keychain does, and then how long once replaced.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let listen = env::args()
+            .nth(1)
+            .or_else(|| env::var("IDMS_LISTEN").ok())
+            .unwrap_or_else(|| DEFAULT_LISTEN.to_owned());
+        let data_dir =
+            env::var_os("IDMS_DATA_DIR").map_or_else(|| PathBuf::from("."), PathBuf::from);
+        let master_key = env::var("IDMS_MASTER_KEY").map_err(|_| ConfigError::MissingMasterKey)?;
+
+        let mut lockout = LockoutPolicy::default();
+        if let Some(max_failures) = env_number("IDMS_MAX_FAILURES")? {
+            lockout.max_failures = max_failures;
+        }
+        if let Some(secs) = env_number("IDMS_LOCKOUT_SECS")? {
+            lockout.lockout = Duration::from_secs(secs.into());
+        }
+        let mut mail = MailPolicy::default();
+        if let Some(max_messages) = env_number("IDMS_MAILBOX_MESSAGES")? {
+            mail.max_messages = max_messages as usize;
+        }
+        if let Some(max_bytes) = env_number("IDMS_MAILBOX_BYTES")? {
+            mail.max_bytes = max_bytes as usize;
+        }
+        if let Some(secs) = env_number("IDMS_MAILBOX_TTL_SECS")? {
+            mail.ttl = Duration::from_secs(secs.into());
+        }
+        let mut keepalive = KeepalivePolicy::default();
+        if let Some(secs) = env_number("IDMS_IDLE_SECS")? {
+            keepalive.idle_after = Duration::from_secs(secs.into());
+        }
+        if let Some(secs) = env_number("IDMS_TIMEOUT_SECS")? {
+            keepalive.timeout = Duration::from_secs(secs.into());
+        }
+        let ticket_lifetime = env_number("IDMS_TICKET_SECS")?
+            .map_or(DEFAULT_TICKET_LIFETIME, |secs| {
+                Duration::from_secs(secs.into())
+            });
+        let key_lifetime =
+            env_number("IDMS_KEY_LIFETIME_SECS")?.map(|secs| Duration::from_secs(secs.into()));
+        let key_grace_period = env_number("IDMS_KEY_GRACE_SECS")?
+            .map_or(DEFAULT_GRACE_PERIOD, |secs| {
+                Duration::from_secs(secs.into())
+            });
+
+        Ok(Self {
+            listen: Listen::parse(&listen),
+            data_dir,
+            master_key: parse_master_key(&master_key).ok_or(ConfigError::BadMasterKey)?,
+            lockout,
+            mail,
+            keepalive,
+            ticket_lifetime,
+            key_lifetime,
+            key_grace_period,
+        })
+    }
+}
+
+fn env_number(name: &'static str) -> Result<Option<u32>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::BadNumber(name)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_master_key(hex: &str) -> Option<[u8; MASTER_KEY_LENGTH]> {
+    if hex.len() != MASTER_KEY_LENGTH * 2 {
+        return None;
+    }
+    let mut key = [0u8; MASTER_KEY_LENGTH];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// One `KeyStore` shared by every connection's guard. The lock is held for
+/// the whole of `rotate_key` and `insert_key`, so they stay atomic.
+pub struct SharedKeys<KS>(Arc<Mutex<KS>>);
+
+impl<KS> SharedKeys<KS> {
+    pub fn new(keys: KS) -> Self {
+        Self(Arc::new(Mutex::new(keys)))
+    }
+}
+
+impl<KS> Clone for SharedKeys<KS> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<KS: KeyStore> KeyStore for SharedKeys<KS> {
+    type ID = KS::ID;
+
+    async fn set_key(
+        &mut self,
+        id: Self::ID,
+        keychain: ForeignKeychain,
+    ) -> Result<Option<ForeignKeychain>, KeyStoreError> {
+        self.0.lock().await.set_key(id, keychain).await
+    }
+
+    async fn get_key(&self, id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError> {
+        self.0.lock().await.get_key(id).await
+    }
+
+    async fn get_previous_key(&self, id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError> {
+        self.0.lock().await.get_previous_key(id).await
+    }
+
+    async fn rotate_key(
+        &mut self,
+        id: Self::ID,
+        keychain: ForeignKeychain,
+    ) -> Result<Option<ForeignKeychain>, KeyStoreError> {
+        self.0.lock().await.rotate_key(id, keychain).await
+    }
+
+    async fn revoke_previous_key(&mut self, id: &Self::ID) -> Result<(), KeyStoreError> {
+        self.0.lock().await.revoke_previous_key(id).await
+    }
+
+    async fn insert_key(
+        &mut self,
+        id: Self::ID,
+        keychain: ForeignKeychain,
+    ) -> Result<(), KeyStoreError> {
+        self.0.lock().await.insert_key(id, keychain).await
+    }
+}
+
+/// One user registry shared by every connection's guard.
+pub struct SharedUsers<US>(Arc<Mutex<US>>);
+
+impl<US> SharedUsers<US> {
+    pub fn new(users: US) -> Self {
+        Self(Arc::new(Mutex::new(users)))
+    }
+}
+
+impl<US> Clone for SharedUsers<US> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<US: CredentialStore> CredentialStore for SharedUsers<US> {
+    async fn iterations(&self) -> NonZeroU32 {
+        self.0.lock().await.iterations().await
+    }
+
+    async fn password_hash(&self, userid: &str) -> Option<PasswordHash> {
+        self.0.lock().await.password_hash(userid).await
+    }
+}
+
+impl<US: UserRegistry> UserRegistry for SharedUsers<US> {
+    async fn create_user(
+        &mut self,
+        user: User,
+        password: PasswordHash,
+    ) -> Result<(), RegistryError> {
+        self.0.lock().await.create_user(user, password).await
+    }
+
+    async fn get_user(&self, id: &str) -> Option<User> {
+        self.0.lock().await.get_user(id).await
+    }
+
+    async fn attach_key(&mut self, id: &str, public_key: [u8; 32]) -> Result<(), RegistryError> {
+        self.0.lock().await.attach_key(id, public_key).await
+    }
+
+    async fn set_status(&mut self, id: &str, status: UserStatus) -> Result<(), RegistryError> {
+        self.0.lock().await.set_status(id, status).await
+    }
+
+    async fn record_failure(
+        &mut self,
+        id: &str,
+        policy: &LockoutPolicy,
+        now: SystemTime,
+    ) -> Result<Option<Duration>, RegistryError> {
+        self.0.lock().await.record_failure(id, policy, now).await
+    }
+
+    async fn clear_failures(&mut self, id: &str) -> Result<(), RegistryError> {
+        self.0.lock().await.clear_failures(id).await
+    }
+
+    async fn delete_user(&mut self, id: &str) -> Result<(), RegistryError> {
+        self.0.lock().await.delete_user(id).await
+    }
+
+    async fn remove_user(&mut self, id: &str) -> Result<(), RegistryError> {
+        self.0.lock().await.remove_user(id).await
+    }
+}
+
+/// Everything the guards of one server share.
+#[derive(Clone)]
+pub struct ServerState {
+    pub guard_key: StaticSecret,
+    pub keys: SharedKeys<FileKeyStore>,
+    pub users: SharedUsers<UserStore>,
+    pub prekeys: Arc<Mutex<PreKeyStore>>,
+    pub mail: Arc<Mutex<MailStore>>,
+    pub presence: Arc<StdMutex<PresenceRegistry>>,
+    pub seen: Arc<Mutex<SeenMessages>>,
+    pub tickets: Arc<StdMutex<TicketKeeper>>,
+    pub registrations: Arc<StdMutex<RateLimit>>,
+    pub lockout: LockoutPolicy,
+    pub keepalive: KeepalivePolicy,
+    pub key_lifetime: Option<Duration>,
+}
+
+/// Refuses to go on unless every keychain belongs to a user. A keychain
+/// with no user behind it would let anyone `Register` the id and take it
+/// over with `Sync`.
+///
+/// Returns the users still in use that have no keychain, e.g. because a
+/// registration was cut short. They are left be: their next `Sync` gives
+/// them one.
+pub fn check_stores(users: &UserStore, keys: &FileKeyStore) -> Result<Vec<String>, StoreError> {
+    for id in keys.ids() {
+        if !users.users().any(|user| user.id == id) {
+            return Err(StoreError::Mismatch(format!(
+                "keychain for unknown user {:?}",
+                id
+            )));
+        }
+    }
+    Ok(users
+        .users()
+        .filter(|user| user.status != UserStatus::Deleted)
+        .filter(|user| !keys.ids().any(|id| id == user.id))
+        .map(|user| user.id.clone())
+        .collect())
+}
+
+/// Loads the guard's long-term key, creating it on first start. Keychains
+/// hold secrets shared with this key, so it has to outlive restarts.
+pub async fn load_guard_key(file: &RecordFile) -> Result<StaticSecret, StoreError> {
+    let mut keys = file.load::<[u8; 32]>()?;
+    if let Some(bytes) = keys.get(GUARD_KEY_ID) {
+        return Ok(StaticSecret::from(*bytes));
+    }
+    let guard_key = StaticSecret::new(OsRng);
+    keys.insert(GUARD_KEY_ID.to_owned(), guard_key.to_bytes());
+    file.save(&keys).await?;
+    Ok(guard_key)
+}
+
+/// A TCP or Unix stream.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+type BoxedStream = Box<dyn Stream>;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn bind(listen: &Listen) -> io::Result<Self> {
+        match listen {
+            Listen::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            Listen::Unix(path) => Ok(Self::Unix(UnixListener::bind(path)?, path.clone())),
+        }
+    }
+
+    pub fn local_addr(&self) -> String {
+        match self {
+            Self::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|_| "tcp".to_owned(), |addr| addr.to_string()),
+            Self::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Returns the peer, for logs, and the source it counts as for rate
+    /// limits: its IP address, whatever the port, or the socket path.
+    async fn accept(&self) -> io::Result<(String, String, BoxedStream)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((peer.to_string(), peer.ip().to_string(), Box::new(stream)))
+            }
+            Self::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                let peer = format!("unix:{}", path.display());
+                Ok((peer.clone(), peer, Box::new(stream)))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Disables, enables or deletes user `id`, for `idms user <action> <id>`.
+/// Only run this with the server stopped: a running one would write its
+/// own copy of the user store back over the change.
+pub async fn manage_user(config: &Config, action: &str, id: &str) -> Result<(), Box<dyn Error>> {
+    let mut users = UserStore::open(config.data_dir.join("users.db"), &config.master_key)?;
+    match action {
+        "disable" => users.disable_user(id).await?,
+        "enable" => users.enable_user(id).await?,
+        "delete" => users.delete_user(id).await?,
+        _ => return Err(format!("unknown user action {:?}", action).into()),
+    }
+    Ok(())
+}
+
+/// Opens the stores in `config.data_dir`, then serves until Ctrl-C.
+pub async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&config.data_dir)?;
+    let guard_key = load_guard_key(&RecordFile::new(
+        config.data_dir.join("guard.key"),
+        &config.master_key,
+    ))
+    .await?;
+    let keys = FileKeyStore::open(config.data_dir.join("keys.db"), &config.master_key)?
+        .with_grace_period(config.key_grace_period);
+    let users = UserStore::open(config.data_dir.join("users.db"), &config.master_key)?;
+    for id in check_stores(&users, &keys)? {
+        eprintln!("idms: user {:?} has no keychain until it syncs", id);
+    }
+    let prekeys = PreKeyStore::open(config.data_dir.join("prekeys.db"), &config.master_key)?;
+    let mail =
+        MailStore::open(config.data_dir.join("mail"), &config.master_key)?.with_policy(config.mail);
+    let seen = SeenMessages::open(config.data_dir.join("seen.db"), &config.master_key)?;
+    let state = ServerState {
+        guard_key,
+        keys: SharedKeys::new(keys),
+        users: SharedUsers::new(users),
+        prekeys: Arc::new(Mutex::new(prekeys)),
+        mail: Arc::new(Mutex::new(mail)),
+        presence: Arc::default(),
+        seen: Arc::new(Mutex::new(seen)),
+        tickets: Arc::new(StdMutex::new(
+            TicketKeeper::default().with_lifetime(config.ticket_lifetime),
+        )),
+        registrations: Arc::new(StdMutex::new(RateLimit::new(
+            MAX_REGISTRATIONS,
+            REGISTRATION_WINDOW,
+        ))),
+        lockout: config.lockout,
+        keepalive: config.keepalive,
+        key_lifetime: config.key_lifetime,
+    };
+
+    let listener = Listener::bind(&config.listen).await?;
+    serve(listener, state, async {
+        tokio::signal::ctrl_c().await.ok();
+    })
+    .await;
+    Ok(())
+}
+
+/// Accepts connections until `shutdown` completes, then closes every open
+/// connection and waits for them to wind down.
+pub async fn serve(listener: Listener, state: ServerState, shutdown: impl Future<Output = ()>) {
+    eprintln!("listening on {}", listener.local_addr());
+    let (stop_tx, stop_rx) = watch::channel(());
+    let mut connections = JoinSet::new();
+    let mut next_id = 0u64;
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((peer, source, stream)) => {
+                    next_id += 1;
+                    let label = format!("#{} {}", next_id, peer);
+                    connections.spawn(connection(
+                        label,
+                        source,
+                        stream,
+                        state.clone(),
+                        stop_rx.clone(),
+                    ));
+                }
+                Err(err) => eprintln!("accept failed: {}", err),
+            },
+            // Reap finished connections so the set doesn't grow forever.
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    eprintln!("shutting down, closing {} connection(s)", connections.len());
+    drop(listener);
+    stop_tx.send(()).ok();
+    while connections.join_next().await.is_some() {}
+}
+
+/// Runs the secure channel handshake as responder, then serves the
+/// connection with every frame sealed under the channel's keys. Clients
+/// that don't complete the handshake in time are dropped.
+async fn connection(
+    label: String,
+    source: String,
+    mut stream: BoxedStream,
+    state: ServerState,
+    mut stop: watch::Receiver<()>,
+) {
+    eprintln!("{}: connected", label);
+    let handshake = secure_channel::respond(&mut stream, &state.guard_key);
+    let transport = tokio::select! {
+        result = tokio::time::timeout(state.keepalive.timeout, handshake) => match result {
+            Ok(Ok(transport)) => transport,
+            Ok(Err(err)) => {
+                eprintln!("{}: handshake failed: {}", label, err);
+                return;
+            }
+            Err(_) => {
+                eprintln!("{}: handshake timed out", label);
+                return;
+            }
+        },
+        _ = stop.changed() => return,
+    };
+    let (sender, receiver) = transport.split();
+    let (reader, writer) = tokio::io::split(stream);
+
+    let (in_tx, in_rx) = mpsc::channel(INBOUND_QUEUE);
+    let guard = SocketGuard::new(in_rx, state.keys, state.users)
+        .with_guard_key(state.guard_key)
+        .with_prekeys(state.prekeys)
+        .with_mail(state.mail)
+        .with_presence(state.presence)
+        .with_seen(state.seen)
+        .with_tickets(state.tickets)
+        .with_registrations(state.registrations)
+        .with_source(source)
+        .with_lockout_policy(state.lockout)
+        .with_keepalive(state.keepalive);
+    let mut guard = match state.key_lifetime {
+        Some(lifetime) => guard.with_key_lifetime(lifetime),
+        None => guard,
+    };
+    let mut replies = guard.replies().unwrap();
+
+    let reading = tokio::spawn(FrameReader::new(reader).forward_sealed(receiver, in_tx));
+    let writing = tokio::spawn(async move {
+        let mut sender = sender;
+        let mut writer = FrameWriter::new(writer);
+        while let Some(reply) = replies.recv().await {
+            writer.write_sealed(&mut sender, &reply.as_sealed()).await?;
+        }
+        Ok::<_, CodecError>(())
+    });
+
+    tokio::select! {
+        _ = guard.serve(|decoded| log_message(&label, &decoded)) => {}
+        _ = stop.changed() => eprintln!("{}: closing for shutdown", label),
+    }
+
+    reading.abort();
+    if let Ok(Err(err)) = reading.await {
+        eprintln!("{}: read failed: {}", label, err);
+    }
+    // Dropping the guard closes the reply queue once it has drained.
+    drop(guard);
+    if let Ok(Err(err)) = writing.await {
+        eprintln!("{}: write failed: {}", label, err);
+    }
+    eprintln!("{}: disconnected", label);
+}
+
+fn log_message(label: &str, decoded: &DecodedMessage) {
+    let len = decoded.message.len();
+    match &decoded.encryption_data {
+        EncryptionData::Passed { username, .. } => {
+            eprintln!("{}: {} byte message from {}", label, len, username)
+        }
+        EncryptionData::Anonymous { username, .. } => {
+            eprintln!("{}: {} byte sealed box claiming {}", label, len, username)
+        }
+        EncryptionData::Expired { username, version } => {
+            eprintln!("{}: {} used expired key v{}", label, username, version)
+        }
+        EncryptionData::Failed { target, encrypted } => {
+            let kind = if *encrypted { "sealed box" } else { "message" };
+            eprintln!("{}: rejected {} for {}", label, kind, target)
+        }
+        EncryptionData::Replayed { username, error } => {
+            eprintln!("{}: message from {}: {}", label, username, error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use idms::secure::secure_channel::{self, Transport};
+    use tokio::io::{ReadHalf, WriteHalf};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{
+        check_stores, manage_user, parse_master_key, serve, Config, Listen, Listener, ServerState,
+        SharedKeys, SharedUsers, DEFAULT_LISTEN,
+    };
+    use crate::codec::{FrameReader, FrameWriter};
+    use crate::limit::RateLimit;
+    use crate::security::{
+        ForeignKeychain, KeyStore, OwnedSealedMessage, SealedMessage, DEFAULT_GRACE_PERIOD,
+    };
+    use crate::store::{FileKeyStore, StoreError};
+    use crate::testing::TempPath;
+    use crate::ticket::DEFAULT_TICKET_LIFETIME;
+    use crate::user::{LockoutPolicy, PasswordHash, User, UserRegistry, UserStatus, UserStore};
+    use crate::{MAX_REGISTRATIONS, REGISTRATION_WINDOW};
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const TEST_PASSWORD: &str = "TEST_PASSWORD";
+
+    fn test_password() -> PasswordHash {
+        PasswordHash::new(TEST_PASSWORD, NonZeroU32::new(1).unwrap())
+    }
+
+    #[test]
+    fn parses_config_values() {
+        assert_eq!(
+            Listen::parse("unix:/tmp/idms.sock"),
+            Listen::Unix("/tmp/idms.sock".into())
+        );
+        assert_eq!(
+            Listen::parse("0.0.0.0:7878"),
+            Listen::Tcp("0.0.0.0:7878".to_owned())
+        );
+        assert_eq!(parse_master_key(&"0a".repeat(32)), Some([10u8; 32]));
+        assert_eq!(parse_master_key("0a"), None);
+        assert_eq!(parse_master_key(&"zz".repeat(32)), None);
+    }
+
+    #[tokio::test]
+    async fn startup_needs_the_stores_to_agree() {
+        let path = TempPath::new("agree");
+        let mut keys = FileKeyStore::open(&path, &[5u8; 32]).unwrap();
+        let mut users = UserStore::with_iterations(NonZeroU32::new(1).unwrap());
+        assert!(check_stores(&users, &keys).unwrap().is_empty());
+
+        // As if the user store had been lost.
+        keys.set_key(
+            TEST_USERNAME.to_owned(),
+            ForeignKeychain::new(PublicKey::from([9u8; 32]), StaticSecret::from([1u8; 32])),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            check_stores(&users, &keys),
+            Err(StoreError::Mismatch(_))
+        ));
+
+        users
+            .create_user(User::new(TEST_USERNAME, TEST_USERNAME), test_password())
+            .await
+            .unwrap();
+        assert!(check_stores(&users, &keys).unwrap().is_empty());
+
+        // As if its registration had stopped before the keychain was stored.
+        users
+            .create_user(User::new("ANOTHER_USER", "ANOTHER_USER"), test_password())
+            .await
+            .unwrap();
+        assert_eq!(check_stores(&users, &keys).unwrap(), ["ANOTHER_USER"]);
+    }
+
+    #[tokio::test]
+    async fn manages_users_while_stopped() {
+        let path = TempPath::new("manage");
+        let config = Config {
+            listen: Listen::parse(DEFAULT_LISTEN),
+            data_dir: path.to_path_buf(),
+            master_key: [5u8; 32],
+            lockout: LockoutPolicy::default(),
+            mail: Default::default(),
+            keepalive: Default::default(),
+            ticket_lifetime: DEFAULT_TICKET_LIFETIME,
+            key_lifetime: None,
+            key_grace_period: DEFAULT_GRACE_PERIOD,
+        };
+        std::fs::create_dir_all(&path).unwrap();
+        let users_db = path.join("users.db");
+        UserStore::open(&users_db, &config.master_key)
+            .unwrap()
+            .create_user(User::new(TEST_USERNAME, TEST_USERNAME), test_password())
+            .await
+            .unwrap();
+
+        manage_user(&config, "disable", TEST_USERNAME)
+            .await
+            .unwrap();
+        assert!(manage_user(&config, "rename", TEST_USERNAME).await.is_err());
+        assert!(manage_user(&config, "disable", "NO_SUCH_USER")
+            .await
+            .is_err());
+        let users = UserStore::open(&users_db, &config.master_key).unwrap();
+        assert_eq!(
+            users.get_user(TEST_USERNAME).await.unwrap().status,
+            UserStatus::Disabled
+        );
+    }
+
+    fn test_state(path: &TempPath) -> ServerState {
+        ServerState {
+            guard_key: StaticSecret::from([1u8; 32]),
+            keys: SharedKeys::new(FileKeyStore::open(path, &[5u8; 32]).unwrap()),
+            users: SharedUsers::new(UserStore::with_iterations(NonZeroU32::new(1).unwrap())),
+            prekeys: Default::default(),
+            mail: Default::default(),
+            presence: Default::default(),
+            seen: Default::default(),
+            tickets: Default::default(),
+            registrations: Arc::new(StdMutex::new(RateLimit::new(
+                MAX_REGISTRATIONS,
+                REGISTRATION_WINDOW,
+            ))),
+            lockout: LockoutPolicy::default(),
+            keepalive: Default::default(),
+            key_lifetime: None,
+        }
+    }
+
+    /// Connects and completes the handshake, as a client would.
+    async fn connect(
+        addr: &str,
+    ) -> (
+        FrameReader<ReadHalf<TcpStream>>,
+        FrameWriter<WriteHalf<TcpStream>>,
+        Transport,
+    ) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let transport = secure_channel::initiate(&mut stream, &StaticSecret::from([3u8; 32]))
+            .await
+            .unwrap();
+        let (reader, writer) = tokio::io::split(stream);
+        (
+            FrameReader::new(reader),
+            FrameWriter::new(writer),
+            transport,
+        )
+    }
+
+    fn register(public_key: &PublicKey) -> SealedMessage<'_> {
+        SealedMessage::Register {
+            userid: TEST_USERNAME,
+            password: TEST_PASSWORD,
+            public_key: public_key.as_bytes(),
+            signing_key: &[9u8; 32],
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_share_one_store() {
+        let path = TempPath::new("server");
+        let listener = Listener::bind(&Listen::Tcp("127.0.0.1:0".to_owned()))
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, test_state(&path), async {
+            stop_rx.await.ok();
+        }));
+
+        // A user registered on one connection is known on the next.
+        let public_key = PublicKey::from(&StaticSecret::from([2u8; 32]));
+        for expected in [
+            OwnedSealedMessage::Ack {
+                userid: TEST_USERNAME.to_owned(),
+            },
+            OwnedSealedMessage::Reject {
+                userid: TEST_USERNAME.to_owned(),
+                reason: "user id is taken".to_owned(),
+            },
+        ] {
+            let (mut reader, mut writer, transport) = connect(&addr).await;
+            let (mut sender, mut receiver) = transport.split();
+            writer
+                .write_sealed(&mut sender, &register(&public_key))
+                .await
+                .unwrap();
+            let reply = reader.read_sealed(&mut receiver).await.unwrap();
+            assert_eq!(reply, Some(expected));
+        }
+
+        // Shutdown closes connections that are still open.
+        let _idle = TcpStream::connect(&addr).await.unwrap();
+        stop_tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_start_with_a_handshake() {
+        let path = TempPath::new("handshake");
+        let listener = Listener::bind(&Listen::Tcp("127.0.0.1:0".to_owned()))
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, test_state(&path), async {
+            stop_rx.await.ok();
+        }));
+        let public_key = PublicKey::from(&StaticSecret::from([2u8; 32]));
+
+        // A client that skips the handshake is hung up on unheard.
+        let (reader, writer) = TcpStream::connect(&addr).await.unwrap().into_split();
+        FrameWriter::new(writer)
+            .write_message(&register(&public_key))
+            .await
+            .unwrap();
+        assert!(!matches!(
+            FrameReader::new(reader).read_owned().await,
+            Ok(Some(_))
+        ));
+
+        // So the id is still free.
+        let (mut reader, mut writer, transport) = connect(&addr).await;
+        assert_eq!(
+            transport.remote_static(),
+            &PublicKey::from(&StaticSecret::from([1u8; 32]))
+        );
+        let (mut sender, mut receiver) = transport.split();
+        writer
+            .write_sealed(&mut sender, &register(&public_key))
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.read_sealed(&mut receiver).await.unwrap(),
+            Some(OwnedSealedMessage::Ack {
+                userid: TEST_USERNAME.to_owned(),
+            })
+        );
+
+        stop_tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
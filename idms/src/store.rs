@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use bincode::Options;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use x25519_dalek::PublicKey;
+
+use crate::security::{
+    ForeignKeychain, KeyStore, KeyStoreError, DEFAULT_GRACE_PERIOD, SHARED_KEY_LENGTH,
+};
+
+pub const MASTER_KEY_LENGTH: usize = 32;
+const RECORD_KEY_INFO: &[u8] = b"idms record file";
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    /// The file or one of its records failed to decode or authenticate,
+    /// e.g. because it was tampered with or the master key is wrong.
+    Corrupt(String),
+    /// Two stores that should agree about an id don't.
+    Mismatch(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "store i/o failed: {}", err),
+            Self::Corrupt(id) if id.is_empty() => write!(f, "store file is corrupt"),
+            Self::Corrupt(id) => write!(f, "record {:?} is corrupt", id),
+            Self::Mismatch(reason) => write!(f, "stores disagree: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<StoreError> for KeyStoreError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::Io(err) => Self::Io(err),
+            other => Self::Corrupt(other.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedRecord {
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    generation: u64,
+    records: BTreeMap<String, SealedRecord>,
+}
+
+/// A map of records kept in one file. Every record is sealed separately
+/// with AES-256-GCM, using its id as associated data so that records cannot
+/// be swapped between ids.
+///
+/// The key is not the master key itself but one derived from it for this
+/// file name and write, numbered by a generation stored alongside. No key
+/// ever seals more than one write's worth of records, which keeps random
+/// nonces far from colliding however long the file lives.
+pub struct RecordFile {
+    path: PathBuf,
+    master_key: [u8; MASTER_KEY_LENGTH],
+    /// The generation last loaded or saved. `save` writes the next one.
+    generation: AtomicU64,
+    rng: SystemRandom,
+}
+
+impl RecordFile {
+    pub fn new(path: impl AsRef<Path>, master_key: &[u8; MASTER_KEY_LENGTH]) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            master_key: *master_key,
+            generation: AtomicU64::new(0),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Reads every record. A missing file is an empty store. Call this
+    /// before the first `save`, which picks up the generation from it.
+    pub fn load<T: DeserializeOwned>(&self) -> Result<HashMap<String, T>, StoreError> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let sealed: SealedFile = bincode::options()
+            .deserialize(&bytes)
+            .map_err(|_| StoreError::Corrupt(String::new()))?;
+        self.generation
+            .fetch_max(sealed.generation, Ordering::SeqCst);
+
+        let key = self.key(sealed.generation);
+        sealed
+            .records
+            .into_iter()
+            .map(|(id, record)| {
+                let value =
+                    open(&key, &id, record).ok_or_else(|| StoreError::Corrupt(id.clone()))?;
+                Ok((id, value))
+            })
+            .collect()
+    }
+
+    /// Replaces the file with `records`. The new contents are written to a
+    /// temporary file and renamed into place, so a crash leaves either the
+    /// old file or the new one. The file I/O runs on tokio's blocking pool.
+    pub async fn save<T: Serialize>(&self, records: &HashMap<String, T>) -> Result<(), StoreError> {
+        // A failed write still uses up its generation, as its key may have
+        // sealed something that reached the disk.
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let key = self.key(generation);
+        let records = records
+            .iter()
+            .map(|(id, value)| Ok((id.clone(), self.seal(&key, id, value)?)))
+            .collect::<Result<BTreeMap<_, _>, StoreError>>()?;
+        let bytes = bincode::options()
+            .serialize(&SealedFile {
+                generation,
+                records,
+            })
+            .unwrap();
+
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || write_atomic(&path, &bytes))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(())
+    }
+
+    /// HKDF over the master key, bound to the file name and `generation`.
+    fn key(&self, generation: u64) -> LessSafeKey {
+        let name = self
+            .path
+            .file_name()
+            .map_or(&[][..], |name| name.as_encoded_bytes());
+        let info = [name, &generation.to_be_bytes()];
+        let mut key = [0u8; 32];
+        Salt::new(HKDF_SHA256, RECORD_KEY_INFO)
+            .extract(&self.master_key)
+            .expand(&info, HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key))
+            .unwrap();
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap())
+    }
+
+    fn seal<T: Serialize>(
+        &self,
+        key: &LessSafeKey,
+        id: &str,
+        value: &T,
+    ) -> Result<SealedRecord, StoreError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| io::Error::other("no randomness available"))?;
+
+        let mut ciphertext = bincode::options().serialize(value).unwrap();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(id.as_bytes()),
+            &mut ciphertext,
+        )
+        .unwrap();
+        Ok(SealedRecord { nonce, ciphertext })
+    }
+}
+
+fn open<T: DeserializeOwned>(key: &LessSafeKey, id: &str, mut record: SealedRecord) -> Option<T> {
+    let plaintext = key
+        .open_in_place(
+            Nonce::assume_unique_for_key(record.nonce),
+            Aad::from(id.as_bytes()),
+            &mut record.ciphertext,
+        )
+        .ok()?;
+    bincode::options().deserialize(plaintext).ok()
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), StoreError> {
+    let mut tmp = path.to_owned().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent().and_then(|dir| File::open(dir).ok()) {
+        dir.sync_all().ok();
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeychainRecord {
+    public_key: [u8; 32],
+    shared_key: [u8; SHARED_KEY_LENGTH],
+    signing_key: Option<[u8; 32]>,
+    version: u32,
+    created_at: SystemTime,
+    expires_at: Option<SystemTime>,
+}
+
+impl From<&ForeignKeychain> for KeychainRecord {
+    fn from(keychain: &ForeignKeychain) -> Self {
+        Self {
+            public_key: keychain.public_key.to_bytes(),
+            shared_key: keychain.shared_key,
+            signing_key: keychain.signing_key,
+            version: keychain.version,
+            created_at: keychain.created_at,
+            expires_at: keychain.expires_at,
+        }
+    }
+}
+
+impl From<KeychainRecord> for ForeignKeychain {
+    fn from(record: KeychainRecord) -> Self {
+        Self {
+            public_key: PublicKey::from(record.public_key),
+            shared_key: record.shared_key,
+            signing_key: record.signing_key,
+            version: record.version,
+            created_at: record.created_at,
+            expires_at: record.expires_at,
+        }
+    }
+}
+
+/// The current keychain for an id and the one it replaced, if any.
+#[derive(Clone)]
+struct KeySlot {
+    current: ForeignKeychain,
+    previous: Option<ForeignKeychain>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeySlotRecord {
+    current: KeychainRecord,
+    previous: Option<KeychainRecord>,
+}
+
+impl From<&KeySlot> for KeySlotRecord {
+    fn from(slot: &KeySlot) -> Self {
+        Self {
+            current: (&slot.current).into(),
+            previous: slot.previous.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl From<KeySlotRecord> for KeySlot {
+    fn from(record: KeySlotRecord) -> Self {
+        Self {
+            current: record.current.into(),
+            previous: record.previous.map(Into::into),
+        }
+    }
+}
+
+/// A `KeyStore` that keeps its keychains in an encrypted `RecordFile`, so
+/// they survive restarts. Every write rewrites the whole file.
+pub struct FileKeyStore {
+    file: RecordFile,
+    keys: HashMap<String, KeySlot>,
+    grace_period: Duration,
+}
+
+impl FileKeyStore {
+    pub fn open(
+        path: impl AsRef<Path>,
+        master_key: &[u8; MASTER_KEY_LENGTH],
+    ) -> Result<Self, StoreError> {
+        let file = RecordFile::new(path, master_key);
+        let keys = file
+            .load::<KeySlotRecord>()?
+            .into_iter()
+            .map(|(id, record)| (id, record.into()))
+            .collect();
+        Ok(Self {
+            file,
+            keys,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        })
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.keys.keys().map(String::as_str)
+    }
+
+    /// How long a replaced keychain keeps being returned by
+    /// `get_previous_key`.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    async fn persist(&self) -> Result<(), StoreError> {
+        let records: HashMap<String, KeySlotRecord> = self
+            .keys
+            .iter()
+            .map(|(id, slot)| (id.clone(), slot.into()))
+            .collect();
+        self.file.save(&records).await
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    type ID = String;
+
+    async fn set_key(
+        &mut self,
+        id: Self::ID,
+        keychain: ForeignKeychain,
+    ) -> Result<Option<ForeignKeychain>, KeyStoreError> {
+        let slot = KeySlot {
+            previous: self
+                .keys
+                .get(&id)
+                .map(|old| old.current.clone().retire(self.grace_period)),
+            current: keychain,
+        };
+        let old = self.keys.insert(id.clone(), slot);
+        if let Err(err) = self.persist().await {
+            // Keep memory in line with what is on disk.
+            match old {
+                Some(old) => self.keys.insert(id, old),
+                None => self.keys.remove(&id),
+            };
+            return Err(err.into());
+        }
+        Ok(old.map(|old| old.current))
+    }
+
+    async fn get_key(&self, id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError> {
+        self.keys
+            .get(id)
+            .map(|slot| slot.current.clone())
+            .ok_or(KeyStoreError::NotFound)
+    }
+
+    async fn get_previous_key(&self, id: &Self::ID) -> Result<ForeignKeychain, KeyStoreError> {
+        self.keys
+            .get(id)
+            .and_then(|slot| slot.previous.as_ref())
+            .filter(|previous| !previous.is_expired(SystemTime::now()))
+            .cloned()
+            .ok_or(KeyStoreError::NotFound)
+    }
+
+    async fn revoke_previous_key(&mut self, id: &Self::ID) -> Result<(), KeyStoreError> {
+        let previous = match self.keys.get_mut(id) {
+            Some(slot) => slot.previous.take(),
+            None => return Err(KeyStoreError::NotFound),
+        };
+        if previous.is_none() {
+            return Ok(());
+        }
+        if let Err(err) = self.persist().await {
+            // Keep memory in line with what is on disk.
+            self.keys.get_mut(id).unwrap().previous = previous;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::{FileKeyStore, StoreError};
+    use crate::security::{ForeignKeychain, KeyStore, KeyStoreError};
+    use crate::testing::TempPath;
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const MASTER_KEY: &[u8; 32] = &[5u8; 32];
+
+    #[tokio::test]
+    async fn file_keystore_survives_reopen() {
+        let path = TempPath::new("keystore");
+        let keychain =
+            ForeignKeychain::new(PublicKey::from([1u8; 32]), StaticSecret::from([2u8; 32]))
+                .with_signing_key([3u8; 32]);
+        let shared_key = keychain.shared_key;
+
+        let mut ks = FileKeyStore::open(&path, MASTER_KEY).unwrap();
+        ks.set_key(TEST_USERNAME.to_owned(), keychain)
+            .await
+            .unwrap();
+        drop(ks);
+
+        // Nothing is stored in the clear.
+        let bytes = fs::read(&path).unwrap();
+        assert!(!bytes.windows(32).any(|window| window == shared_key));
+
+        let ks = FileKeyStore::open(&path, MASTER_KEY).unwrap();
+        let keychain = ks.get_key(&TEST_USERNAME.to_owned()).await.unwrap();
+        assert_eq!(keychain.public_key.as_bytes(), &[1u8; 32]);
+        assert_eq!(keychain.shared_key, shared_key);
+        assert_eq!(keychain.signing_key, Some([3u8; 32]));
+
+        assert!(matches!(
+            FileKeyStore::open(&path, &[6u8; 32]),
+            Err(StoreError::Corrupt(_))
+        ));
+        // Keys are per file, so records don't open under another name.
+        let copy = TempPath::new("keystore-copy");
+        fs::copy(&path, &copy).unwrap();
+        assert!(matches!(
+            FileKeyStore::open(&copy, MASTER_KEY),
+            Err(StoreError::Corrupt(_))
+        ));
+
+        let mut tampered = bytes;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        fs::write(&path, tampered).unwrap();
+        assert!(matches!(
+            FileKeyStore::open(&path, MASTER_KEY),
+            Err(StoreError::Corrupt(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn file_keystore_keeps_previous_key() {
+        let path = TempPath::new("rotation");
+        let id = TEST_USERNAME.to_owned();
+        let keychain =
+            |byte| ForeignKeychain::new(PublicKey::from([byte; 32]), StaticSecret::from([2u8; 32]));
+
+        let mut ks = FileKeyStore::open(&path, MASTER_KEY).unwrap();
+        ks.rotate_key(id.clone(), keychain(1)).await.unwrap();
+        assert!(matches!(
+            ks.get_previous_key(&id).await,
+            Err(KeyStoreError::NotFound)
+        ));
+
+        let old = ks.rotate_key(id.clone(), keychain(4)).await.unwrap();
+        assert_eq!(old.unwrap().version, 1);
+        drop(ks);
+
+        let mut ks = FileKeyStore::open(&path, MASTER_KEY).unwrap();
+        let current = ks.get_key(&id).await.unwrap();
+        assert_eq!(current.public_key.as_bytes(), &[4u8; 32]);
+        assert_eq!(current.version, 2);
+        let previous = ks.get_previous_key(&id).await.unwrap();
+        assert_eq!(previous.public_key.as_bytes(), &[1u8; 32]);
+        assert!(previous.expires_at.is_some());
+
+        // Revoking ends the grace period early, for good.
+        ks.revoke_previous_key(&id).await.unwrap();
+        drop(ks);
+        let ks = FileKeyStore::open(&path, MASTER_KEY).unwrap();
+        assert!(matches!(
+            ks.get_previous_key(&id).await,
+            Err(KeyStoreError::NotFound)
+        ));
+        assert_eq!(ks.get_key(&id).await.unwrap().version, 2);
+
+        // Without a grace period the old key is gone as soon as it is replaced.
+        let mut ks = ks.with_grace_period(Duration::ZERO);
+        ks.rotate_key(id.clone(), keychain(5)).await.unwrap();
+        assert!(matches!(
+            ks.get_previous_key(&id).await,
+            Err(KeyStoreError::NotFound)
+        ));
+    }
+}
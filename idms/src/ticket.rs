@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use bincode::Options;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+pub const TICKET_ID_LEN: usize = 16;
+pub const DEFAULT_TICKET_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TicketError {
+    /// Not a ticket this server issued, or issued to someone else.
+    Invalid,
+    Expired,
+    /// Already used to resume once.
+    Redeemed,
+    /// The user has synced a new key since the ticket was issued.
+    Stale,
+    /// The resuming client doesn't hold the ticket's secret.
+    BadProof,
+}
+
+impl fmt::Display for TicketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "invalid ticket"),
+            Self::Expired => write!(f, "ticket expired, sync instead"),
+            Self::Redeemed => write!(f, "ticket already used"),
+            Self::Stale => write!(f, "ticket is for an old key, sync instead"),
+            Self::BadProof => write!(f, "resumption proof does not match"),
+        }
+    }
+}
+
+impl std::error::Error for TicketError {}
+
+/// What a ticket carries, readable by the server alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticket {
+    pub id: [u8; TICKET_ID_LEN],
+    pub userid: String,
+    /// The version of the keychain it was issued under.
+    pub key_id: u32,
+    pub secret: [u8; 32],
+    pub expires_at: SystemTime,
+}
+
+/// Issues and redeems session tickets. Tickets are sealed with AES-256-GCM
+/// under a key made up at startup and never stored, so a restart voids
+/// every ticket along with the record of which were used.
+pub struct TicketKeeper {
+    key: LessSafeKey,
+    rng: SystemRandom,
+    lifetime: Duration,
+    /// Tickets redeemed so far, until they would have expired anyway.
+    redeemed: HashMap<[u8; TICKET_ID_LEN], SystemTime>,
+}
+
+impl Default for TicketKeeper {
+    fn default() -> Self {
+        let rng = SystemRandom::new();
+        let mut key = [0u8; 32];
+        rng.fill(&mut key).expect("no randomness available");
+        Self {
+            key: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap()),
+            rng,
+            lifetime: DEFAULT_TICKET_LIFETIME,
+            redeemed: HashMap::new(),
+        }
+    }
+}
+
+impl TicketKeeper {
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Seals a ticket for `userid`'s keychain `key_id`. The nonce goes
+    /// first, and the user id is associated data, so a ticket can't be
+    /// presented for anyone else.
+    pub fn issue(&self, userid: &str, key_id: u32, secret: [u8; 32], now: SystemTime) -> Vec<u8> {
+        let mut id = [0u8; TICKET_ID_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut id).expect("no randomness available");
+        self.rng.fill(&mut nonce).expect("no randomness available");
+        let ticket = Ticket {
+            id,
+            userid: userid.to_owned(),
+            key_id,
+            secret,
+            expires_at: now + self.lifetime,
+        };
+
+        let mut sealed = bincode::options().serialize(&ticket).unwrap();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(userid.as_bytes()),
+                &mut sealed,
+            )
+            .unwrap();
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    /// Reads a ticket presented for `userid`, provided it is still good.
+    /// It stays good until `redeem`.
+    pub fn open(
+        &self,
+        userid: &str,
+        ticket: &[u8],
+        now: SystemTime,
+    ) -> Result<Ticket, TicketError> {
+        if ticket.len() < NONCE_LEN {
+            return Err(TicketError::Invalid);
+        }
+        let (nonce, sealed) = ticket.split_at(NONCE_LEN);
+        let mut sealed = sealed.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).unwrap(),
+                Aad::from(userid.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| TicketError::Invalid)?;
+        let ticket: Ticket = bincode::options()
+            .deserialize(plaintext)
+            .map_err(|_| TicketError::Invalid)?;
+
+        if ticket.expires_at <= now {
+            return Err(TicketError::Expired);
+        }
+        if self.redeemed.contains_key(&ticket.id) {
+            return Err(TicketError::Redeemed);
+        }
+        Ok(ticket)
+    }
+
+    /// Uses up `ticket`. Fails if it already was.
+    pub fn redeem(&mut self, ticket: &Ticket, now: SystemTime) -> Result<(), TicketError> {
+        // Expired tickets are turned away anyway, so no need to remember
+        // them.
+        self.redeemed.retain(|_, expires_at| *expires_at > now);
+        if self.redeemed.insert(ticket.id, ticket.expires_at).is_some() {
+            return Err(TicketError::Redeemed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{TicketError, TicketKeeper};
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+
+    #[test]
+    fn tickets_are_single_use_and_time_bounded() {
+        let mut keeper = TicketKeeper::default().with_lifetime(Duration::from_secs(60));
+        let now = SystemTime::now();
+        let ticket = keeper.issue(TEST_USERNAME, 3, [7u8; 32], now);
+
+        // Only for the user it was issued to, and only by this keeper.
+        assert_eq!(
+            keeper.open("SOMEONE_ELSE", &ticket, now).unwrap_err(),
+            TicketError::Invalid
+        );
+        assert_eq!(
+            TicketKeeper::default()
+                .open(TEST_USERNAME, &ticket, now)
+                .unwrap_err(),
+            TicketError::Invalid
+        );
+        assert_eq!(
+            keeper
+                .open(TEST_USERNAME, &ticket, now + Duration::from_secs(60))
+                .unwrap_err(),
+            TicketError::Expired
+        );
+
+        let opened = keeper.open(TEST_USERNAME, &ticket, now).unwrap();
+        assert_eq!((opened.key_id, opened.secret), (3, [7u8; 32]));
+        keeper.redeem(&opened, now).unwrap();
+        assert_eq!(
+            keeper.open(TEST_USERNAME, &ticket, now).unwrap_err(),
+            TicketError::Redeemed
+        );
+        assert_eq!(keeper.redeem(&opened, now), Err(TicketError::Redeemed));
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use ring::digest::SHA256_OUTPUT_LEN;
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{RecordFile, StoreError, MASTER_KEY_LENGTH};
+
+const SALT_LEN: usize = 16;
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+
+/// A salted PBKDF2-HMAC-SHA256 password hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordHash {
+    iterations: u32,
+    salt: [u8; SALT_LEN],
+    hash: [u8; SHA256_OUTPUT_LEN],
+}
+
+impl PasswordHash {
+    pub fn new(password: &str, iterations: NonZeroU32) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).unwrap();
+
+        let mut hash = [0u8; SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+
+        Self {
+            iterations: iterations.get(),
+            salt,
+            hash,
+        }
+    }
+
+    /// A hash no password matches, that takes as long to check as a real
+    /// one. Checked in place of a missing user's, so that response times
+    /// don't give away which ids exist.
+    pub fn unmatchable(iterations: NonZeroU32) -> Self {
+        Self {
+            iterations: iterations.get(),
+            salt: [0u8; SALT_LEN],
+            hash: [0u8; SHA256_OUTPUT_LEN],
+        }
+    }
+
+    /// Constant-time comparison against a candidate password.
+    pub fn verify(&self, password: &str) -> bool {
+        match NonZeroU32::new(self.iterations) {
+            Some(iterations) => pbkdf2::verify(
+                PBKDF2_HMAC_SHA256,
+                iterations,
+                &self.salt,
+                password.as_bytes(),
+                &self.hash,
+            )
+            .is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Hands out password hashes rather than checking passwords itself, so
+/// that callers can run PBKDF2 off the async runtime and without holding
+/// any lock on the store.
+pub trait CredentialStore {
+    /// How many PBKDF2 iterations new password hashes should use.
+    async fn iterations(&self) -> NonZeroU32;
+
+    async fn password_hash(&self, userid: &str) -> Option<PasswordHash>;
+}
+
+/// Limits on password guessing at `Sync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Failures in a row before the account locks.
+    pub max_failures: u32,
+    /// The first lockout. Each failure past `max_failures` doubles it.
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+    /// The wait a connection gets after its first failure, doubling with
+    /// each further one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl LockoutPolicy {
+    pub fn lockout_for(&self, failures: u32) -> Option<Duration> {
+        let past = failures.checked_sub(self.max_failures)?;
+        Some(double(self.lockout, past).min(self.max_lockout))
+    }
+
+    pub fn backoff_for(&self, failures: u32) -> Duration {
+        match failures {
+            0 => Duration::ZERO,
+            n => double(self.backoff, n - 1).min(self.max_backoff),
+        }
+    }
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(60 * 60),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+fn double(base: Duration, times: u32) -> Duration {
+    base.saturating_mul(1u32.checked_shl(times).unwrap_or(u32::MAX))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UserStatus {
+    Active,
+    Disabled,
+    /// Kept as a tombstone so the id cannot be registered again.
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub display_name: String,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+    pub status: UserStatus,
+    pub public_keys: Vec<[u8; 32]>,
+    /// Wrong passwords since the last successful `Sync`.
+    pub failed_logins: u32,
+    pub locked_until: Option<SystemTime>,
+}
+
+impl User {
+    pub fn new(id: &str, display_name: &str) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id: id.to_owned(),
+            display_name: display_name.to_owned(),
+            created_at: now,
+            updated_at: now,
+            status: UserStatus::Active,
+            public_keys: Vec::new(),
+            failed_logins: 0,
+            locked_until: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == UserStatus::Active
+    }
+
+    /// How long the account stays locked, if it is.
+    pub fn locked_for(&self, now: SystemTime) -> Option<Duration> {
+        self.locked_until?
+            .duration_since(now)
+            .ok()
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Counts a wrong password, locking the account once the policy says
+    /// so. Returns the new lock, if any.
+    pub fn record_failure(&mut self, policy: &LockoutPolicy, now: SystemTime) -> Option<Duration> {
+        self.failed_logins = self.failed_logins.saturating_add(1);
+        let lockout = policy.lockout_for(self.failed_logins);
+        if let Some(lockout) = lockout {
+            self.locked_until = Some(now + lockout);
+        }
+        self.touch();
+        lockout
+    }
+
+    fn touch(&mut self) {
+        self.updated_at = SystemTime::now();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    AlreadyExists,
+    NotFound,
+    Inactive(UserStatus),
+    /// The change couldn't be written out, so it wasn't made.
+    Store,
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyExists => write!(f, "user id is taken"),
+            Self::NotFound => write!(f, "no such user"),
+            Self::Inactive(UserStatus::Disabled) => write!(f, "user is disabled"),
+            Self::Inactive(_) => write!(f, "user is deleted"),
+            Self::Store => write!(f, "user store unavailable, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<StoreError> for RegistryError {
+    fn from(_: StoreError) -> Self {
+        Self::Store
+    }
+}
+
+pub trait UserRegistry {
+    /// Creates `user` along with its password, in one write, so a failure
+    /// leaves neither behind.
+    async fn create_user(
+        &mut self,
+        user: User,
+        password: PasswordHash,
+    ) -> Result<(), RegistryError>;
+    /// Returns a copy, so that registries behind a lock can hand one out.
+    async fn get_user(&self, id: &str) -> Option<User>;
+    async fn attach_key(&mut self, id: &str, public_key: [u8; 32]) -> Result<(), RegistryError>;
+    async fn set_status(&mut self, id: &str, status: UserStatus) -> Result<(), RegistryError>;
+
+    /// Applies `User::record_failure` to the stored user.
+    async fn record_failure(
+        &mut self,
+        id: &str,
+        policy: &LockoutPolicy,
+        now: SystemTime,
+    ) -> Result<Option<Duration>, RegistryError>;
+
+    /// Forgets past failures and any lock, after a successful `Sync`.
+    async fn clear_failures(&mut self, id: &str) -> Result<(), RegistryError>;
+
+    /// Drops the user's password and the public keys listed on it, leaving
+    /// a `Deleted` record so the id can't be registered again. Keychains,
+    /// prekeys and mail live in their own stores and stay there, unused,
+    /// since the guard turns away inactive users.
+    async fn delete_user(&mut self, id: &str) -> Result<(), RegistryError>;
+
+    /// Forgets `id` altogether, for undoing a `create_user` whose keychain
+    /// couldn't be stored. Unlike `delete_user`, this frees the id.
+    async fn remove_user(&mut self, id: &str) -> Result<(), RegistryError>;
+
+    /// Fails with `NotFound` or `Inactive` unless the user can act.
+    async fn check_active(&self, id: &str) -> Result<User, RegistryError> {
+        match self.get_user(id).await {
+            Some(user) if user.is_active() => Ok(user),
+            Some(user) => Err(RegistryError::Inactive(user.status)),
+            None => Err(RegistryError::NotFound),
+        }
+    }
+
+    async fn disable_user(&mut self, id: &str) -> Result<(), RegistryError> {
+        self.set_status(id, UserStatus::Disabled).await
+    }
+
+    async fn enable_user(&mut self, id: &str) -> Result<(), RegistryError> {
+        self.set_status(id, UserStatus::Active).await
+    }
+}
+
+/// What is stored for one id: the user, its password, or both.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct UserRecord {
+    user: Option<User>,
+    password: Option<PasswordHash>,
+}
+
+/// Users and their password hashes. Opened on a file, every change,
+/// lockouts included, is written out before it takes effect, so a restart
+/// forgets neither who exists nor who is locked out.
+pub struct UserStore {
+    file: Option<RecordFile>,
+    iterations: NonZeroU32,
+    records: HashMap<String, UserRecord>,
+}
+
+impl UserStore {
+    pub fn with_iterations(iterations: NonZeroU32) -> Self {
+        Self {
+            file: None,
+            iterations,
+            records: HashMap::new(),
+        }
+    }
+
+    pub fn open(
+        path: impl AsRef<Path>,
+        master_key: &[u8; MASTER_KEY_LENGTH],
+    ) -> Result<Self, StoreError> {
+        let file = RecordFile::new(path, master_key);
+        let records = file.load()?;
+        Ok(Self {
+            file: Some(file),
+            records,
+            ..Self::default()
+        })
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.records
+            .values()
+            .filter_map(|record| record.user.as_ref())
+    }
+
+    fn record(&self, id: &str) -> UserRecord {
+        self.records.get(id).cloned().unwrap_or_default()
+    }
+
+    /// `id`'s record, provided it has a user.
+    fn user_record(&self, id: &str) -> Result<(UserRecord, User), RegistryError> {
+        let record = self.record(id);
+        let user = record.user.clone().ok_or(RegistryError::NotFound)?;
+        Ok((record, user))
+    }
+
+    async fn commit(&mut self, id: &str, record: UserRecord) -> Result<(), StoreError> {
+        let old = self.records.insert(id.to_owned(), record);
+        if let Some(file) = &self.file {
+            if let Err(err) = file.save(&self.records).await {
+                // Keep memory in line with what is on disk.
+                match old {
+                    Some(old) => self.records.insert(id.to_owned(), old),
+                    None => self.records.remove(id),
+                };
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn commit_user(
+        &mut self,
+        id: &str,
+        record: UserRecord,
+        mut user: User,
+    ) -> Result<(), RegistryError> {
+        user.touch();
+        let record = UserRecord {
+            user: Some(user),
+            ..record
+        };
+        Ok(self.commit(id, record).await?)
+    }
+}
+
+impl Default for UserStore {
+    fn default() -> Self {
+        Self::with_iterations(NonZeroU32::new(DEFAULT_ITERATIONS).unwrap())
+    }
+}
+
+impl CredentialStore for UserStore {
+    async fn iterations(&self) -> NonZeroU32 {
+        self.iterations
+    }
+
+    async fn password_hash(&self, userid: &str) -> Option<PasswordHash> {
+        self.records
+            .get(userid)
+            .and_then(|record| record.password.clone())
+    }
+}
+
+impl UserRegistry for UserStore {
+    async fn create_user(
+        &mut self,
+        user: User,
+        password: PasswordHash,
+    ) -> Result<(), RegistryError> {
+        if self.records.contains_key(&user.id) {
+            return Err(RegistryError::AlreadyExists);
+        }
+        let id = user.id.clone();
+        let record = UserRecord {
+            user: Some(user),
+            password: Some(password),
+        };
+        Ok(self.commit(&id, record).await?)
+    }
+
+    async fn get_user(&self, id: &str) -> Option<User> {
+        self.records.get(id).and_then(|record| record.user.clone())
+    }
+
+    async fn attach_key(&mut self, id: &str, public_key: [u8; 32]) -> Result<(), RegistryError> {
+        let (record, mut user) = self.user_record(id)?;
+        if user.public_keys.contains(&public_key) {
+            return Ok(());
+        }
+        user.public_keys.push(public_key);
+        self.commit_user(id, record, user).await
+    }
+
+    async fn set_status(&mut self, id: &str, status: UserStatus) -> Result<(), RegistryError> {
+        let (record, mut user) = self.user_record(id)?;
+        if user.status == UserStatus::Deleted {
+            return Err(RegistryError::Inactive(UserStatus::Deleted));
+        }
+        user.status = status;
+        self.commit_user(id, record, user).await
+    }
+
+    async fn record_failure(
+        &mut self,
+        id: &str,
+        policy: &LockoutPolicy,
+        now: SystemTime,
+    ) -> Result<Option<Duration>, RegistryError> {
+        let (record, mut user) = self.user_record(id)?;
+        let lockout = user.record_failure(policy, now);
+        self.commit_user(id, record, user).await?;
+        Ok(lockout)
+    }
+
+    async fn clear_failures(&mut self, id: &str) -> Result<(), RegistryError> {
+        let (record, mut user) = self.user_record(id)?;
+        if user.failed_logins == 0 && user.locked_until.is_none() {
+            return Ok(());
+        }
+        user.failed_logins = 0;
+        user.locked_until = None;
+        self.commit_user(id, record, user).await
+    }
+
+    async fn delete_user(&mut self, id: &str) -> Result<(), RegistryError> {
+        let (record, mut user) = self.user_record(id)?;
+        if user.status == UserStatus::Deleted {
+            return Err(RegistryError::Inactive(UserStatus::Deleted));
+        }
+        user.status = UserStatus::Deleted;
+        user.public_keys.clear();
+        let record = UserRecord {
+            password: None,
+            ..record
+        };
+        self.commit_user(id, record, user).await
+    }
+
+    async fn remove_user(&mut self, id: &str) -> Result<(), RegistryError> {
+        let old = self.records.remove(id).ok_or(RegistryError::NotFound)?;
+        if let Some(file) = &self.file {
+            if let Err(err) = file.save(&self.records).await {
+                self.records.insert(id.to_owned(), old);
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::{Duration, SystemTime};
+
+    use super::{
+        CredentialStore, LockoutPolicy, PasswordHash, RegistryError, User, UserRegistry,
+        UserStatus, UserStore,
+    };
+    use crate::testing::TempPath;
+
+    const TEST_USERNAME: &str = "TEST_USERNAME";
+    const TEST_PASSWORD: &str = "TEST_PASSWORD";
+    const MASTER_KEY: &[u8; 32] = &[5u8; 32];
+
+    fn test_password() -> PasswordHash {
+        PasswordHash::new(TEST_PASSWORD, NonZeroU32::new(1).unwrap())
+    }
+
+    #[tokio::test]
+    async fn user_lifecycle() {
+        let mut store = UserStore::with_iterations(NonZeroU32::new(1).unwrap());
+        assert_eq!(
+            store.check_active(TEST_USERNAME).await.unwrap_err(),
+            RegistryError::NotFound
+        );
+
+        store
+            .create_user(User::new(TEST_USERNAME, "Test User"), test_password())
+            .await
+            .unwrap();
+        store.attach_key(TEST_USERNAME, [1u8; 32]).await.unwrap();
+        assert_eq!(
+            store
+                .create_user(User::new(TEST_USERNAME, "Impostor"), test_password())
+                .await,
+            Err(RegistryError::AlreadyExists)
+        );
+        assert_eq!(
+            store.check_active(TEST_USERNAME).await.unwrap().public_keys,
+            vec![[1u8; 32]]
+        );
+
+        store.disable_user(TEST_USERNAME).await.unwrap();
+        assert_eq!(
+            store.check_active(TEST_USERNAME).await.unwrap_err(),
+            RegistryError::Inactive(UserStatus::Disabled)
+        );
+        store.enable_user(TEST_USERNAME).await.unwrap();
+        assert!(store.check_active(TEST_USERNAME).await.is_ok());
+
+        store.delete_user(TEST_USERNAME).await.unwrap();
+        let user = store.get_user(TEST_USERNAME).await.unwrap();
+        assert_eq!(user.status, UserStatus::Deleted);
+        assert!(user.public_keys.is_empty());
+        assert!(user.updated_at >= user.created_at);
+        assert_eq!(
+            store.enable_user(TEST_USERNAME).await,
+            Err(RegistryError::Inactive(UserStatus::Deleted))
+        );
+        assert!(store.password_hash(TEST_USERNAME).await.is_none());
+        let iterations = store.iterations().await;
+        assert!(!PasswordHash::unmatchable(iterations).verify(""));
+
+        // Removing, unlike deleting, frees the id.
+        store.remove_user(TEST_USERNAME).await.unwrap();
+        assert!(store.get_user(TEST_USERNAME).await.is_none());
+        store
+            .create_user(User::new(TEST_USERNAME, "Test User"), test_password())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lockout_backs_off_exponentially() {
+        let policy = LockoutPolicy {
+            max_failures: 3,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(25),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+        };
+        assert_eq!(policy.backoff_for(0), Duration::ZERO);
+        assert_eq!(policy.backoff_for(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff_for(40), Duration::from_secs(3));
+
+        let now = SystemTime::now();
+        let mut store = UserStore::with_iterations(NonZeroU32::new(1).unwrap());
+        store
+            .create_user(User::new(TEST_USERNAME, "Test User"), test_password())
+            .await
+            .unwrap();
+        let mut locks = Vec::new();
+        for _ in 0..5 {
+            locks.push(
+                store
+                    .record_failure(TEST_USERNAME, &policy, now)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            locks,
+            [
+                None,
+                None,
+                Some(Duration::from_secs(10)),
+                Some(Duration::from_secs(20)),
+                Some(Duration::from_secs(25)),
+            ]
+        );
+        let user = store.get_user(TEST_USERNAME).await.unwrap();
+        assert_eq!(user.locked_for(now), Some(Duration::from_secs(25)));
+        assert_eq!(user.locked_for(now + Duration::from_secs(25)), None);
+
+        store.clear_failures(TEST_USERNAME).await.unwrap();
+        let user = store.get_user(TEST_USERNAME).await.unwrap();
+        assert_eq!((user.failed_logins, user.locked_for(now)), (0, None));
+    }
+
+    #[tokio::test]
+    async fn user_store_survives_reopen() {
+        let path = TempPath::new("users");
+        let policy = LockoutPolicy {
+            max_failures: 1,
+            ..LockoutPolicy::default()
+        };
+        let now = SystemTime::now();
+
+        let mut store = UserStore::open(&path, MASTER_KEY).unwrap();
+        store
+            .create_user(User::new(TEST_USERNAME, "Test User"), test_password())
+            .await
+            .unwrap();
+        store
+            .record_failure(TEST_USERNAME, &policy, now)
+            .await
+            .unwrap();
+        drop(store);
+
+        // The password and the lock both outlive a restart.
+        let store = UserStore::open(&path, MASTER_KEY).unwrap();
+        let hash = store.password_hash(TEST_USERNAME).await.unwrap();
+        assert!(hash.verify(TEST_PASSWORD));
+        let user = store.get_user(TEST_USERNAME).await.unwrap();
+        assert_eq!(user.locked_for(now), Some(policy.lockout));
+    }
+}
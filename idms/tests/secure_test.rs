@@ -1,27 +1,383 @@
 #[cfg(test)]
 mod test {
 
-    use idms::secure::sym::SymContext;
-    use ring::aead::{CHACHA20_POLY1305, NonceSequence, AES_256_GCM};
+    use idms::secure::keys::SharedKey;
+    use idms::secure::ratchet::{Header, RatchetError, Session, HEADER_LEN};
+    use idms::secure::red_box;
+    use idms::secure::seal::{Seal, SealError, Sealable};
+    use idms::secure::secure_channel::{initiate, respond};
+    use idms::secure::x3dh::{self, PreKeyBundle};
+    use idms::secure::sym::{DecryptError, RekeyPolicy, Role, StreamError, SymContext, REPLAY_WINDOW, STREAM_CHUNK, STREAM_SALT_LEN};
+    use rand_core::OsRng;
+    use ring::aead::{Algorithm, AES_256_GCM, CHACHA20_POLY1305};
+    use ring::error::Unspecified;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde::{Deserialize, Serialize};
+    use x25519_dalek::{PublicKey, StaticSecret};
 
     #[test]
     fn sym_context() {
         static PAYLOAD: [u8; 4] = [1, 2, 3, 4];
 
         let mut sc = SymContext::new([1u8; 32], &AES_256_GCM);
-        let sc2 = sc.clone();
+        let mut sc2 = sc.clone();
         assert_eq!(sc.peek(), sc2.peek());
-        sc.advance().unwrap();
+        let mut other: Vec<u8> = PAYLOAD.clone().to_vec();
+        assert_eq!(sc2.encrypt(&[], &mut other).unwrap(), 0);
         assert_eq!(sc.peek(), sc2.peek());
 
         let mut payload: Vec<u8> = PAYLOAD.clone().to_vec();
-        sc.encrypt(&mut payload);
+        let counter = sc.encrypt(&[], &mut payload).unwrap();
+        assert_eq!(counter, 1);
 
         println!("{:?}, {:?}", PAYLOAD, payload);
         assert_ne!(payload, PAYLOAD);
 
-        sc.decrypt(&mut payload).unwrap();
+        // The clone's message never arrives, so the receiver opens by counter.
+        let mut peer = SymContext::new([1u8; 32], &AES_256_GCM);
+        assert_eq!(peer.decrypt(&[], &mut payload.clone()), Err(Unspecified));
+        assert_eq!(peer.decrypt_at(counter, b"other", &mut payload.clone()), Err(DecryptError::Unauthentic));
+        assert_eq!(peer.decrypt_at(counter, &[], &mut payload).unwrap(), PAYLOAD);
         
         println!("{:?}, {:?}", PAYLOAD, payload);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sym_context_rejects_replays() {
+        static PAYLOAD: &[u8] = b"Hello World";
+
+        for alg in [&AES_256_GCM, &CHACHA20_POLY1305] {
+            let alg: &'static Algorithm = alg;
+            let mut sender = SymContext::new([1u8; 32], alg);
+            let mut receiver = SymContext::new([1u8; 32], alg);
+
+            let sealed: Vec<(u64, Vec<u8>)> = (0..REPLAY_WINDOW + 2).map(|_| {
+                let mut payload = PAYLOAD.to_vec();
+                (sender.encrypt(b"header", &mut payload).unwrap(), payload)
+            }).collect();
+
+            // Out of order is fine, but only once per counter.
+            let (counter, payload) = &sealed[1];
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()).unwrap(), PAYLOAD);
+            let (counter, payload) = &sealed[0];
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()).unwrap(), PAYLOAD);
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()), Err(DecryptError::Replayed));
+
+            // A forgery doesn't use up its counter.
+            let (counter, payload) = &sealed[2];
+            let mut forged = payload.clone();
+            forged[0] ^= 1;
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut forged), Err(DecryptError::Unauthentic));
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()).unwrap(), PAYLOAD);
+
+            // Jumping ahead pushes the oldest counters out of the window.
+            let (counter, payload) = sealed.last().unwrap();
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()).unwrap(), PAYLOAD);
+            let (counter, payload) = &sealed[3];
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()).unwrap(), PAYLOAD);
+            let (counter, payload) = &sealed[1];
+            assert_eq!(receiver.decrypt_at(*counter, b"header", &mut payload.clone()), Err(DecryptError::TooOld));
+        }
+    }
+
+    #[test]
+    fn sym_context_separates_directions_and_rekeys() {
+        static PAYLOAD: &[u8] = b"Hello World";
+        let rekey = RekeyPolicy { messages: 4, bytes: 1024 };
+
+        let mut initiator = SymContext::with_role([1u8; 32], &CHACHA20_POLY1305, Role::Initiator).with_rekey(rekey);
+        let mut responder = SymContext::with_role([1u8; 32], &CHACHA20_POLY1305, Role::Responder).with_rekey(rekey);
+
+        // Both ends seal at counter 0, but under different nonces, and
+        // neither opens its own messages.
+        let mut sent = PAYLOAD.to_vec();
+        let mut reply = PAYLOAD.to_vec();
+        initiator.encrypt(&[], &mut sent).unwrap();
+        responder.encrypt(&[], &mut reply).unwrap();
+        assert_ne!(sent, reply);
+        assert_eq!(initiator.clone().decrypt(&[], &mut sent.clone()), Err(Unspecified));
+        assert_eq!(responder.decrypt(&[], &mut sent).unwrap(), PAYLOAD);
+        assert_eq!(initiator.decrypt(&[], &mut reply).unwrap(), PAYLOAD);
+
+        // Every four messages move to a new key.
+        let mut sealed = vec![(0, PAYLOAD.to_vec())];
+        for _ in 1..6 {
+            let mut payload = PAYLOAD.to_vec();
+            sealed.push((initiator.encrypt(&[], &mut payload).unwrap(), payload));
+        }
+        assert_eq!(initiator.epoch(), 1);
+
+        // A large message uses up the epoch's bytes, so the next one skips
+        // ahead to a fresh key.
+        let mut payload = vec![0u8; 2000];
+        sealed.push((initiator.encrypt(&[], &mut payload).unwrap(), payload));
+        let mut payload = PAYLOAD.to_vec();
+        sealed.push((initiator.encrypt(&[], &mut payload).unwrap(), payload));
+        assert_eq!(sealed.last().unwrap().0, 8);
+        assert_eq!(initiator.epoch(), 2);
+
+        // An in-order receiver follows along.
+        for (_, payload) in &sealed[1..] {
+            responder.decrypt(&[], &mut payload.clone()).unwrap();
+        }
+
+        // Once a receiver has ratcheted, the older keys are gone.
+        let mut late = SymContext::with_role([1u8; 32], &CHACHA20_POLY1305, Role::Responder).with_rekey(rekey);
+        let (counter, payload) = sealed.last().unwrap();
+        assert_eq!(late.decrypt_at(*counter, &[], &mut payload.clone()).unwrap(), PAYLOAD);
+        let (counter, payload) = &sealed[1];
+        assert_eq!(late.decrypt_at(*counter, &[], &mut payload.clone()), Err(DecryptError::TooOld));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn sym_context_is_shared_across_tasks() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SymContext>();
+
+        let ctx = SymContext::new([1u8; 32], &CHACHA20_POLY1305);
+        let tasks: Vec<_> = (0..8).map(|_| {
+            let mut ctx = ctx.clone();
+            tokio::spawn(async move {
+                (0..100).map(|_| ctx.encrypt(&[], &mut b"Hello World".to_vec()).unwrap()).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        let mut counters = Vec::new();
+        for task in tasks {
+            counters.extend(task.await.unwrap());
+        }
+        counters.sort_unstable();
+        counters.dedup();
+        assert_eq!(counters.len(), 800);
+        assert_eq!(ctx.peek(), 800);
+    }
+
+    #[tokio::test]
+    async fn sym_context_streams() {
+        let payload: Vec<u8> = (0..STREAM_CHUNK * 2 + 100).map(|i| i as u8).collect();
+        let sender = SymContext::with_role([1u8; 32], &CHACHA20_POLY1305, Role::Initiator);
+        let receiver = SymContext::with_role([1u8; 32], &CHACHA20_POLY1305, Role::Responder);
+
+        let mut sealed = Vec::new();
+        assert_eq!(sender.seal_stream(b"file", &mut payload.as_slice(), &mut sealed).await.unwrap(), payload.len() as u64);
+        let mut opened = Vec::new();
+        receiver.open_stream(b"file", &mut sealed.as_slice(), &mut opened).await.unwrap();
+        assert_eq!(opened, payload);
+
+        // Dropping the last chunk, or cutting into it, is noticed.
+        let frame = 5 + STREAM_CHUNK + 16;
+        for len in [STREAM_SALT_LEN + 2 * frame, sealed.len() - 1] {
+            let result = receiver.open_stream(b"file", &mut &sealed[..len], &mut Vec::new()).await;
+            assert!(matches!(result, Err(StreamError::Truncated)));
+        }
+
+        // So is swapping two chunks around.
+        let mut reordered = sealed.clone();
+        let (first, second) = reordered[STREAM_SALT_LEN..].split_at_mut(frame);
+        first.swap_with_slice(&mut second[..frame]);
+        let result = receiver.open_stream(b"file", &mut reordered.as_slice(), &mut Vec::new()).await;
+        assert!(matches!(result, Err(StreamError::Unauthentic)));
+
+        // A stream only opens in the direction it was sent.
+        let result = sender.open_stream(b"file", &mut sealed.as_slice(), &mut Vec::new()).await;
+        assert!(matches!(result, Err(StreamError::Unauthentic)));
+    }
+
+    #[tokio::test]
+    async fn secure_channel_handshake() {
+        static PAYLOAD: &[u8] = b"Hello World";
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let client_key = StaticSecret::new(OsRng);
+        let server_key = StaticSecret::new(OsRng);
+
+        let (client_transport, server_transport) = tokio::join!(
+            initiate(&mut client, &client_key),
+            respond(&mut server, &server_key),
+        );
+        let client_transport = client_transport.unwrap();
+        let server_transport = server_transport.unwrap();
+
+        assert_eq!(client_transport.remote_static().as_bytes(), PublicKey::from(&server_key).as_bytes());
+        assert_eq!(server_transport.remote_static().as_bytes(), PublicKey::from(&client_key).as_bytes());
+
+        let (mut client_tx, mut client_rx) = client_transport.split();
+        let (mut server_tx, mut server_rx) = server_transport.split();
+
+        let mut payload = PAYLOAD.to_vec();
+        client_tx.encrypt(&[], &mut payload).unwrap();
+        assert_eq!(server_rx.decrypt(&[], &mut payload).unwrap(), PAYLOAD);
+
+        let mut payload = PAYLOAD.to_vec();
+        server_tx.encrypt(&[], &mut payload).unwrap();
+        assert!(server_rx.clone().decrypt(&[], &mut payload.clone()).is_err());
+        assert_eq!(client_rx.decrypt(&[], &mut payload).unwrap(), PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn secure_channel_rejects_tampering() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, mut relay) = tokio::io::duplex(1024);
+        let client_key = StaticSecret::new(OsRng);
+
+        let responder = async {
+            // Answer with a well-formed message whose encrypted static key is garbage.
+            let mut first = [0u8; 34];
+            relay.read_exact(&mut first).await.unwrap();
+            relay.write_u16(96).await.unwrap();
+            relay.write_all(PublicKey::from(&StaticSecret::new(OsRng)).as_bytes()).await.unwrap();
+            relay.write_all(&[0u8; 64]).await.unwrap();
+        };
+
+        let (result, _) = tokio::join!(initiate(&mut client, &client_key), responder);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn red_box_round_trip() {
+        static PAYLOAD: &[u8] = b"Hello World";
+
+        let recipient = StaticSecret::new(OsRng);
+        let sealed = red_box::seal(&PublicKey::from(&recipient), b"header", PAYLOAD);
+        assert_eq!(red_box::open(&recipient, b"header", &sealed).unwrap(), PAYLOAD);
+
+        assert!(red_box::open(&StaticSecret::new(OsRng), b"header", &sealed).is_err());
+        assert!(red_box::open(&recipient, b"other", &sealed).is_err());
+        assert!(red_box::open(&recipient, b"header", &sealed[..16]).is_err());
+    }
+
+    #[test]
+    fn shared_key_schedule() {
+        static PAYLOAD: &[u8] = b"Hello World";
+
+        let alice = StaticSecret::new(OsRng);
+        let bob = StaticSecret::new(OsRng);
+        let alice_key = SharedKey::derive_stat(PublicKey::from(&bob), alice.clone());
+        let bob_key = SharedKey::derive_stat(PublicKey::from(&alice), bob);
+
+        // Each direction has its own key.
+        let mut payload = PAYLOAD.to_vec();
+        alice_key.sender().encrypt(&[], &mut payload).unwrap();
+        assert!(bob_key.sender().decrypt(&[], &mut payload.clone()).is_err());
+        assert!(alice_key.receiver().decrypt(&[], &mut payload.clone()).is_err());
+        assert_eq!(bob_key.receiver().decrypt(&[], &mut payload).unwrap(), PAYLOAD);
+
+        let (mut ours, mut theirs, mut other) = ([0u8; 48], [0u8; 48], [0u8; 48]);
+        alice_key.export(b"test", &mut ours);
+        bob_key.export(b"test", &mut theirs);
+        bob_key.export(b"other", &mut other);
+        assert_eq!(ours, theirs);
+        assert_ne!(ours, other);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Token(String, u64);
+
+    impl Sealable for Token {
+        const DOMAIN: &'static [u8] = b"idms test token";
+    }
+
+    /// Encodes just like `Token`.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Grant(String, u64);
+
+    impl Sealable for Grant {
+        const DOMAIN: &'static [u8] = b"idms test grant";
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let alice = StaticSecret::new(OsRng);
+        let bob = StaticSecret::new(OsRng);
+        let alice_key = SharedKey::derive_stat(PublicKey::from(&bob), alice.clone()).mac_key();
+        let bob_key = SharedKey::derive_stat(PublicKey::from(&alice), bob).mac_key();
+
+        let token = Token("alice".to_string(), 42);
+        let seal = Seal::new(&token, &alice_key).unwrap();
+        assert!(seal.verify(&bob_key));
+        assert_eq!(seal.open(&bob_key).unwrap(), token);
+
+        // Whatever is changed on the way, it doesn't open.
+        let bytes = bincode::serialize(&seal).unwrap();
+        for at in [8, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[at] ^= 1;
+            let tampered: Seal<Token> = bincode::deserialize(&tampered).unwrap();
+            assert!(matches!(tampered.open(&bob_key), Err(SealError::Forged)));
+        }
+
+        let stranger = SharedKey::derive_stat(PublicKey::from(&alice), StaticSecret::new(OsRng)).mac_key();
+        assert!(!seal.verify(&stranger));
+
+        // Nor does it open as another type.
+        let grant: Seal<Grant> = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(grant.open(&bob_key), Err(SealError::Forged)));
+    }
+
+    #[test]
+    fn ratchet_session() {
+        let alice = StaticSecret::new(OsRng);
+        let bob = StaticSecret::new(OsRng);
+        let bob_ratchet = StaticSecret::new(OsRng);
+        let mut alice_session = Session::initiate(&SharedKey::derive_stat(PublicKey::from(&bob), alice.clone()), PublicKey::from(&bob_ratchet));
+        let mut bob_session = Session::respond(&SharedKey::derive_stat(PublicKey::from(&alice), bob), bob_ratchet);
+        assert_eq!(bob_session.encrypt(b"bob", b"too soon"), Err(RatchetError::NotReady));
+
+        // Out of order is fine, but each message opens once.
+        let sent: Vec<Vec<u8>> = (0..3u8).map(|i| alice_session.encrypt(b"alice", &[i]).unwrap()).collect();
+        assert_eq!(bob_session.decrypt(b"alice", &sent[2]).unwrap(), [2]);
+        assert_eq!(bob_session.decrypt(b"alice", &sent[0]).unwrap(), [0]);
+        assert_eq!(bob_session.decrypt(b"alice", &sent[0]), Err(RatchetError::Unauthentic));
+
+        // A forgery leaves the session where it was.
+        let mut forged = sent[1].clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(bob_session.decrypt(b"alice", &forged), Err(RatchetError::Unauthentic));
+        assert_eq!(bob_session.decrypt(b"other", &sent[1]), Err(RatchetError::Unauthentic));
+        assert_eq!(bob_session.decrypt(b"alice", &sent[1]).unwrap(), [1]);
+
+        // Each reply moves the sender on to a new ratchet key.
+        let reply = bob_session.encrypt(b"bob", b"Hello World").unwrap();
+        assert_eq!(alice_session.decrypt(b"bob", &reply).unwrap(), b"Hello World");
+        let next = alice_session.encrypt(b"alice", &[3]).unwrap();
+        let header = |message: &[u8]| Header::decode(&message[..HEADER_LEN]).unwrap();
+        assert_ne!(header(&next).public, header(&sent[0]).public);
+        assert_eq!(header(&next).previous, 3);
+        assert_eq!(bob_session.decrypt(b"alice", &next).unwrap(), [3]);
+    }
+
+    #[test]
+    fn x3dh_agrees_offline() {
+        let alice = StaticSecret::new(OsRng);
+        let bob = StaticSecret::new(OsRng);
+        let bob_signing = Ed25519KeyPair::from_seed_unchecked(&[3u8; 32]).unwrap();
+        let signed_prekey = StaticSecret::new(OsRng);
+        let one_time_prekey = StaticSecret::new(OsRng);
+
+        let mut bundle = PreKeyBundle {
+            identity_key: PublicKey::from(&bob),
+            signing_key: bob_signing.public_key().as_ref().try_into().unwrap(),
+            signed_prekey: PublicKey::from(&signed_prekey),
+            prekey_signature: bob_signing.sign(PublicKey::from(&signed_prekey).as_bytes()).as_ref().to_vec(),
+            one_time_prekey: Some(PublicKey::from(&one_time_prekey)),
+        };
+        let (alice_key, ephemeral) = x3dh::initiate(&alice, &bundle).unwrap();
+        let bob_key = x3dh::respond(&bob, &signed_prekey, Some(&one_time_prekey), &PublicKey::from(&alice), &ephemeral);
+
+        let mut payload = b"Hello World".to_vec();
+        alice_key.sender().encrypt(&[], &mut payload).unwrap();
+        assert_eq!(bob_key.receiver().decrypt(&[], &mut payload).unwrap(), b"Hello World");
+
+        // Without the one-time prekey the recipient gets a different key.
+        let wrong = x3dh::respond(&bob, &signed_prekey, None, &PublicKey::from(&alice), &ephemeral);
+        let (mut ours, mut theirs) = ([0u8; 32], [0u8; 32]);
+        alice_key.export(b"test", &mut ours);
+        wrong.export(b"test", &mut theirs);
+        assert_ne!(ours, theirs);
+
+        // A prekey the recipient didn't sign is refused.
+        bundle.signed_prekey = PublicKey::from(&StaticSecret::new(OsRng));
+        assert!(x3dh::initiate(&alice, &bundle).is_err());
+    }
+}